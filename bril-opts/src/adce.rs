@@ -0,0 +1,167 @@
+use std::collections::HashSet;
+
+use bril_rs::{Code, EffectOps, Function, Instruction, Program};
+use petgraph::graph::NodeIndex;
+use petgraph::Direction;
+
+use crate::analysis::{instruction_liveness, reaching_definitions};
+use crate::cfg::{graph_from_function, CFG};
+use crate::dominator::{control_dependence, post_dominator_analysis};
+use crate::effects::{summarize, Summaries};
+use crate::utils::{BasicBlock, CFGNode, CF};
+
+/// aggressive dead code elimination, keeps only instructions that lead to an observable effect
+/// and turns branches that nothing depends on into jumps
+pub fn adce(prog: Program) -> Program {
+    let summaries = summarize(&prog);
+    Program {
+        functions: prog
+            .functions
+            .into_iter()
+            .map(|x| adce_function(x, &summaries))
+            .collect(),
+        imports: prog.imports,
+    }
+}
+
+fn adce_function(func: Function, summaries: &Summaries) -> Function {
+    let mut cfg = graph_from_function(&func);
+    adce_cfg(&mut cfg, summaries);
+    Function {
+        instrs: cfg.flatten(),
+        ..func
+    }
+}
+
+/// runs ADCE on [cfg], then removes assignments that are dead across blocks
+pub fn adce_cfg(cfg: &mut CFG<BasicBlock>, summaries: &Summaries) {
+    let live = mark(cfg, summaries);
+    sweep(cfg, &live);
+    remove_dead_assignments(cfg, summaries);
+    for block in cfg.graph.node_weights_mut() {
+        (block.uses, block.defs) = block.uses_and_defs();
+    }
+    cfg.recompute_defs();
+}
+
+/// instructions of [cfg] that lead to an observable effect: the effects themselves, returns,
+/// the definitions their arguments read and the branches deciding whether they run
+fn mark(cfg: &CFG<BasicBlock>, summaries: &Summaries) -> HashSet<(NodeIndex, usize)> {
+    let (post_dominators, exit) = post_dominator_analysis(cfg);
+    let cdg = control_dependence(cfg, &post_dominators);
+    let (reaching, _) = reaching_definitions(cfg);
+
+    let mut work_list = vec![];
+    for node in cfg.graph.node_indices() {
+        let block = cfg.graph.node_weight(node).unwrap();
+        for (i, code) in block.instructions.iter().enumerate() {
+            let critical = match code.control_flow() {
+                CF::Return => true,
+                //a branch is only replaced by a jump to its post-dominator, which the exit isn't
+                CF::Branch(..) => post_dominators.immediate_dominator(node) == Some(exit),
+                _ => !summaries.code(code).is_removable(),
+            };
+            if critical {
+                work_list.push((node, i));
+            }
+        }
+    }
+
+    let mut live = HashSet::new();
+    while let Some((node, i)) = work_list.pop() {
+        if !live.insert((node, i)) {
+            continue;
+        }
+        let block = cfg.graph.node_weight(node).unwrap();
+        //the definitions of each argument that reach it
+        for var in block.instructions[i].uses() {
+            match (0..i)
+                .rev()
+                .find(|j| block.instructions[*j].defs().contains(&var))
+            {
+                Some(j) => work_list.push((node, j)),
+                None => work_list.extend(
+                    reaching[node.index()]
+                        .iter()
+                        .filter(|x| x.var == var)
+                        .filter_map(|x| x.index.map(|j| (x.block, j))),
+                ),
+            }
+        }
+        //the branches deciding whether the block runs
+        for branch in cdg.neighbors_directed(node, Direction::Incoming) {
+            let len = cfg.graph.node_weight(branch).unwrap().instructions.len();
+            if len > 0 {
+                work_list.push((branch, len - 1));
+            }
+        }
+    }
+    live
+}
+
+/// removes the instructions of [cfg] that aren't [live]. a dead branch becomes a jump to its
+/// immediate post-dominator, since nothing live runs in between
+fn sweep(cfg: &mut CFG<BasicBlock>, live: &HashSet<(NodeIndex, usize)>) {
+    let (post_dominators, _) = post_dominator_analysis(cfg);
+    for node in cfg.graph.node_indices().collect::<Vec<_>>() {
+        let block = cfg.graph.node_weight(node).unwrap();
+        let dead_branch = match block.control_flow() {
+            CF::Branch(..) => !live.contains(&(node, block.instructions.len() - 1)),
+            _ => false,
+        };
+        let target = post_dominators
+            .immediate_dominator(node)
+            .filter(|_| dead_branch);
+        let jump = target.map(|x| {
+            Code::Instruction(Instruction::Effect {
+                args: vec![],
+                funcs: vec![],
+                labels: vec![cfg.graph.node_weight(x).unwrap().label.clone().unwrap()],
+                op: EffectOps::Jump,
+                pos: None,
+            })
+        });
+
+        let block = cfg.graph.node_weight_mut(node).unwrap();
+        let mut i = 0;
+        block.instructions.retain(|code| {
+            i += 1;
+            live.contains(&(node, i - 1)) || matches!(code.control_flow(), CF::Jump(_))
+        });
+        if let (Some(target), Some(jump)) = (target, jump) {
+            block.instructions.push(jump);
+            while let Some(edge) = cfg.graph.first_edge(node, Direction::Outgoing) {
+                cfg.graph.remove_edge(edge);
+            }
+            cfg.graph.add_edge(node, target, false);
+        }
+    }
+    cfg.delete_unreachable();
+}
+
+/// removes assignments whose destination is dead afterwards, found with liveness at every
+/// instruction. unlike [crate::tdce::global_pass], this also finds an assignment that is
+/// overwritten in another block before it is read
+fn remove_dead_assignments(cfg: &mut CFG<BasicBlock>, summaries: &Summaries) {
+    loop {
+        let live = instruction_liveness(cfg);
+        let mut changed = false;
+        for node in cfg.graph.node_indices().collect::<Vec<_>>() {
+            let live = &live[node.index()];
+            let block = cfg.graph.node_weight_mut(node).unwrap();
+            let mut i = 0;
+            block.instructions.retain(|code| {
+                i += 1;
+                let dead = !code.defs().is_empty()
+                    && code.defs().iter().all(|x| !live[i].contains(x))
+                    && summaries.code(code).is_removable();
+                changed |= dead;
+                !dead
+            });
+            (block.uses, block.defs) = block.uses_and_defs();
+        }
+        if !changed {
+            break;
+        }
+    }
+}
@@ -0,0 +1,319 @@
+use std::collections::{HashMap, HashSet};
+
+use bril_rs::{Code, EffectOps, Instruction, Literal, Type, ValueOps};
+use petgraph::algo::tarjan_scc;
+use petgraph::graph::NodeIndex;
+
+use crate::cfg::{Dir, CFG};
+use crate::dataflow::{instruction_facts, solve, Analysis};
+use crate::utils::BasicBlock;
+
+/// an abstract memory location a pointer can refer to
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Loc {
+    /// memory returned by the `alloc` at this instruction of a block
+    Alloc(NodeIndex, usize),
+    /// memory the function didn't allocate itself, from an argument, a call or a load
+    Unknown,
+}
+
+/// offset of a pointer from the start of its allocation
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Offset {
+    Known(i64),
+    Any,
+}
+
+impl Offset {
+    fn join(self, other: Offset) -> Offset {
+        match (self, other) {
+            (Offset::Known(a), Offset::Known(b)) if a == b => self,
+            _ => Offset::Any,
+        }
+    }
+
+    fn overlaps(self, other: Offset) -> bool {
+        match (self, other) {
+            (Offset::Known(a), Offset::Known(b)) => a == b,
+            _ => true,
+        }
+    }
+}
+
+/// locations a pointer may refer to, each with the offsets it may have into it
+pub type Targets = HashMap<Loc, Offset>;
+
+/// targets of every pointer variable at each point of a function
+pub struct PointsTo {
+    /// `facts[block][i]` holds just before instruction `i` of the block, as in
+    /// [instruction_facts]
+    pub facts: Vec<Vec<HashMap<String, Targets>>>,
+    //locations reachable by code outside the function or through memory
+    escaped: HashSet<Loc>,
+    //allocation sites that run at most once, so every pointer to them points to the same memory
+    single: HashSet<Loc>,
+}
+
+impl PointsTo {
+    /// targets of [var] just before instruction [index] of [node]. a variable that isn't known
+    /// to be a pointer may point anywhere
+    pub fn targets(&self, node: NodeIndex, index: usize, var: &str) -> Targets {
+        match self.facts[node.index()][index].get(var) {
+            Some(targets) => targets.clone(),
+            None => unknown(),
+        }
+    }
+
+    /// returns true if pointers to [a] and [b] may refer to the same memory
+    pub fn may_alias(&self, a: &Targets, b: &Targets) -> bool {
+        a.iter().any(|(la, oa)| {
+            b.iter().any(|(lb, ob)| match (la, lb) {
+                (Loc::Unknown, Loc::Unknown) => true,
+                (Loc::Unknown, loc) | (loc, Loc::Unknown) => self.escaped.contains(loc),
+                _ => la == lb && oa.overlaps(*ob),
+            })
+        })
+    }
+
+    /// returns true if pointers to [a] and [b] always refer to the same memory
+    pub fn must_alias(&self, a: &Targets, b: &Targets) -> bool {
+        match (a.iter().next(), b.iter().next()) {
+            (Some((la, Offset::Known(oa))), Some((lb, Offset::Known(ob)))) => {
+                a.len() == 1 && b.len() == 1 && la == lb && oa == ob && self.single.contains(la)
+            }
+            _ => false,
+        }
+    }
+
+    /// returns true if [loc] is an allocation site that runs at most once
+    pub fn allocates_once(&self, loc: &Loc) -> bool {
+        self.single.contains(loc)
+    }
+
+    /// returns true if memory at [a] may be read or written by a call
+    pub fn escapes(&self, a: &Targets) -> bool {
+        a.keys()
+            .any(|x| *x == Loc::Unknown || self.escaped.contains(x))
+    }
+}
+
+fn unknown() -> Targets {
+    HashMap::from([(Loc::Unknown, Offset::Any)])
+}
+
+fn join_targets(targets: &mut Targets, other: &Targets) {
+    for (loc, offset) in other {
+        targets
+            .entry(loc.clone())
+            .and_modify(|x| *x = x.join(*offset))
+            .or_insert(*offset);
+    }
+}
+
+/// joins the targets of every pointer in [other] into [fact]
+fn join(fact: &mut HashMap<String, Targets>, other: &HashMap<String, Targets>) {
+    for (var, targets) in other {
+        join_targets(fact.entry(var.clone()).or_default(), targets);
+    }
+}
+
+/// integer variables that are only ever assigned one constant
+fn constants(cfg: &CFG<BasicBlock>) -> HashMap<String, i64> {
+    let mut values = HashMap::<String, Option<i64>>::new();
+    for arg in &cfg.args {
+        values.insert(arg.name.clone(), None);
+    }
+    for block in cfg.graph.node_weights() {
+        for (dest, _) in block.phi.values() {
+            values.insert(dest.clone(), None);
+        }
+        for code in &block.instructions {
+            let (dest, value) = match code {
+                Code::Instruction(Instruction::Constant {
+                    dest,
+                    value: Literal::Int(n),
+                    ..
+                }) => (dest, Some(*n)),
+                Code::Instruction(Instruction::Constant { dest, .. })
+                | Code::Instruction(Instruction::Value { dest, .. }) => (dest, None),
+                _ => continue,
+            };
+            values
+                .entry(dest.clone())
+                .and_modify(|x| {
+                    if *x != value {
+                        *x = None
+                    }
+                })
+                .or_insert(value);
+        }
+    }
+    values
+        .into_iter()
+        .filter_map(|(var, value)| value.map(|x| (var, x)))
+        .collect()
+}
+
+/// targets of every pointer after [code], instruction [index] of [node]
+fn step(
+    node: NodeIndex,
+    index: usize,
+    code: &Code,
+    fact: &HashMap<String, Targets>,
+    constants: &HashMap<String, i64>,
+) -> HashMap<String, Targets> {
+    let mut out = fact.clone();
+    let (dest, targets) = match code {
+        Code::Instruction(Instruction::Value {
+            dest,
+            op,
+            args,
+            op_type,
+            ..
+        }) => match op {
+            ValueOps::Alloc => (
+                dest,
+                HashMap::from([(Loc::Alloc(node, index), Offset::Known(0))]),
+            ),
+            ValueOps::Id => (dest, fact.get(&args[0]).cloned().unwrap_or_default()),
+            ValueOps::PtrAdd => {
+                let step = constants.get(&args[1]);
+                let targets = fact
+                    .get(&args[0])
+                    .cloned()
+                    .unwrap_or_else(unknown)
+                    .into_iter()
+                    .map(|(loc, offset)| match (offset, step) {
+                        (Offset::Known(a), Some(b)) => {
+                            (loc, a.checked_add(*b).map_or(Offset::Any, Offset::Known))
+                        }
+                        _ => (loc, Offset::Any),
+                    })
+                    .collect();
+                (dest, targets)
+            }
+            _ if matches!(op_type, Type::Pointer(_)) => (dest, unknown()),
+            _ => (dest, HashMap::new()),
+        },
+        Code::Instruction(Instruction::Constant { dest, .. }) => (dest, HashMap::new()),
+        _ => return out,
+    };
+    match targets.is_empty() {
+        true => out.remove(dest),
+        false => out.insert(dest.clone(), targets),
+    };
+    out
+}
+
+/// flow-sensitive points-to analysis of [cfg] over allocation sites and constant `ptradd`
+/// offsets. pointers from arguments, calls and loads refer to [Loc::Unknown], which aliases
+/// every allocation that escapes through a call, a store or a `ret`
+pub fn points_to(cfg: &CFG<BasicBlock>) -> PointsTo {
+    let constants = constants(cfg);
+    let transfer = |node: NodeIndex, block: &BasicBlock, fact: &HashMap<String, Targets>| {
+        let mut fact = fact.clone();
+        //phis read the targets of their sources at the end of the predecessors, which are still
+        //the same at the start of the block
+        let phis = block
+            .phi
+            .values()
+            .map(|(dest, sources)| {
+                let mut targets = Targets::new();
+                for (var, _) in sources {
+                    join_targets(&mut targets, fact.get(var).unwrap_or(&Targets::new()));
+                }
+                (dest.clone(), targets)
+            })
+            .collect::<Vec<_>>();
+        for (dest, targets) in phis {
+            if !targets.is_empty() {
+                fact.insert(dest, targets);
+            }
+        }
+        for (i, code) in block.instructions.iter().enumerate() {
+            fact = step(node, i, code, &fact, &constants);
+        }
+        fact
+    };
+    let problem = Analysis {
+        direction: Dir::Forward,
+        top: HashMap::new(),
+        boundary: cfg
+            .args
+            .iter()
+            .filter(|x| matches!(x.arg_type, Type::Pointer(_)))
+            .map(|x| (x.name.clone(), unknown()))
+            .collect(),
+        meet: join,
+        transfer,
+    };
+    let solution = solve(cfg, &problem);
+    let facts = instruction_facts(cfg, &problem, &solution, |node, i, code, fact| {
+        step(node, i, code, fact, &constants)
+    });
+
+    let mut escaped = HashSet::new();
+    for node in cfg.graph.node_indices() {
+        let block = cfg.graph.node_weight(node).unwrap();
+        for (i, code) in block.instructions.iter().enumerate() {
+            let leaked = match code {
+                Code::Instruction(Instruction::Value {
+                    op: ValueOps::Call,
+                    args,
+                    ..
+                })
+                | Code::Instruction(Instruction::Effect {
+                    op: EffectOps::Call | EffectOps::Return,
+                    args,
+                    ..
+                }) => args.iter().collect::<Vec<_>>(),
+                Code::Instruction(Instruction::Effect {
+                    op: EffectOps::Store,
+                    args,
+                    ..
+                }) => vec![&args[1]],
+                _ => vec![],
+            };
+            for var in leaked {
+                if let Some(targets) = facts[node.index()][i].get(var) {
+                    escaped.extend(targets.keys().cloned());
+                }
+            }
+        }
+    }
+
+    //a site in a cycle of the CFG can allocate several times
+    let cyclic = tarjan_scc(&cfg.graph)
+        .into_iter()
+        .filter(|x| x.len() > 1 || cfg.graph.find_edge(x[0], x[0]).is_some())
+        .flatten()
+        .collect::<HashSet<_>>();
+    let single = cfg
+        .graph
+        .node_indices()
+        .filter(|x| !cyclic.contains(x))
+        .flat_map(|node| {
+            let block = cfg.graph.node_weight(node).unwrap();
+            block
+                .instructions
+                .iter()
+                .enumerate()
+                .filter(|(_, code)| {
+                    matches!(
+                        code,
+                        Code::Instruction(Instruction::Value {
+                            op: ValueOps::Alloc,
+                            ..
+                        })
+                    )
+                })
+                .map(move |(i, _)| Loc::Alloc(node, i))
+        })
+        .collect();
+
+    PointsTo {
+        facts,
+        escaped,
+        single,
+    }
+}
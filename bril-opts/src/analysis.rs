@@ -1,44 +1,195 @@
 use crate::cfg::{graph_from_function, Dir, CFG};
-use crate::utils::CFGNode;
+use crate::dataflow::{instruction_facts, intersection, solve, union, Analysis, Dataflow};
+use crate::utils::{BasicBlock, CFGNode};
 use bril_rs::Program;
 use petgraph::graph::NodeIndex;
-use std::collections::HashSet;
-use std::fmt::Debug;
-use std::hash::Hash;
+use std::collections::{HashMap, HashSet};
 
-fn union<T>(a: &HashSet<T>, b: &HashSet<T>) -> HashSet<T>
-where
-    T: Eq + Hash + Clone,
-{
-    a.union(b).cloned().collect()
+/// variables read before they are assigned, going backward from the exits
+fn liveness<T: CFGNode>() -> impl Dataflow<T, Fact = HashSet<String>> {
+    Analysis {
+        direction: Dir::Backward,
+        top: HashSet::new(),
+        boundary: HashSet::new(),
+        meet: union,
+        transfer: |_, block: &T, live: &HashSet<String>| {
+            block
+                .uses()
+                .union(&(live - &block.defs()))
+                .cloned()
+                .collect()
+        },
+    }
 }
 
-fn intersection<T>(a: &HashSet<T>, b: &HashSet<T>) -> HashSet<T>
-where
-    T: Eq + Hash + Clone,
-{
-    a.intersection(b).cloned().collect()
+pub fn live_variable_analysis<T: CFGNode + Clone + std::fmt::Debug + std::fmt::Display>(
+    cfg: &CFG<T>,
+) -> (Vec<HashSet<String>>, Vec<HashSet<String>>) {
+    solve(cfg, &liveness())
 }
 
-fn live_var_transfer<T: CFGNode + Clone + Debug>(
-    l: &HashSet<String>,
-    n: &NodeIndex,
-    cfg: &CFG<T>,
-) -> HashSet<String> {
-    let n = cfg.graph.node_weight(*n).unwrap();
-    n.uses().union(&(l - &n.defs())).cloned().collect()
+/// variables live before each instruction of every block. `live[block][i]` is the live-in set
+/// of instruction `i` and `live[block][i + 1]` its live-out set, so `live[block][len]` is the
+/// live-out set of the block
+pub fn instruction_liveness(cfg: &CFG<BasicBlock>) -> Vec<Vec<HashSet<String>>> {
+    let problem = liveness();
+    let solution = solve(cfg, &problem);
+    instruction_facts(cfg, &problem, &solution, |_, _, code, live| {
+        code.uses().union(&(live - &code.defs())).cloned().collect()
+    })
 }
 
-pub fn live_variable_analysis<T: CFGNode + Clone + std::fmt::Debug + std::fmt::Display>(
+/// positions `[start, end]` of the instructions over which [var] holds a value that may still
+/// be read, in the numbering of [linear_order]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LiveRange {
+    pub var: String,
+    pub intervals: Vec<(usize, usize)>,
+}
+
+/// numbers the instructions of [cfg] block by block, in the order blocks appear in the function
+pub fn linear_order(cfg: &CFG<BasicBlock>) -> Vec<(NodeIndex, usize)> {
+    cfg.graph
+        .node_indices()
+        .flat_map(|node| {
+            let len = cfg.graph.node_weight(node).unwrap().instructions.len();
+            (0..len).map(move |i| (node, i))
+        })
+        .collect()
+}
+
+/// live ranges of every variable from the facts of [instruction_liveness]. a variable occupies
+/// an instruction it is live into or out of, or that assigns it, so a dead assignment still gets
+/// a range of its own. ranges are sorted by variable
+pub fn live_ranges(cfg: &CFG<BasicBlock>, live: &[Vec<HashSet<String>>]) -> Vec<LiveRange> {
+    let mut ranges = HashMap::<String, Vec<(usize, usize)>>::new();
+    for (pos, (node, i)) in linear_order(cfg).into_iter().enumerate() {
+        let code = &cfg.graph.node_weight(node).unwrap().instructions[i];
+        let facts = &live[node.index()];
+        let occupied = facts[i]
+            .iter()
+            .chain(&facts[i + 1])
+            .cloned()
+            .chain(code.defs())
+            .collect::<HashSet<_>>();
+        for var in occupied {
+            let intervals = ranges.entry(var).or_default();
+            match intervals.last_mut() {
+                Some((_, end)) if *end + 1 == pos => *end = pos,
+                _ => intervals.push((pos, pos)),
+            }
+        }
+    }
+    let mut ranges = ranges
+        .into_iter()
+        .map(|(var, intervals)| LiveRange { var, intervals })
+        .collect::<Vec<_>>();
+    ranges.sort_by(|a, b| a.var.cmp(&b.var));
+    ranges
+}
+
+/// variables assigned on every path from the entry to the start and end of each block
+pub fn defined_variable_analysis<T: CFGNode + Clone + std::fmt::Debug + std::fmt::Display>(
     cfg: &CFG<T>,
 ) -> (Vec<HashSet<String>>, Vec<HashSet<String>>) {
-    cfg.work_list(
-        union,
-        live_var_transfer,
-        HashSet::<String>::new(),
-        HashSet::<String>::new(),
-        Dir::Backward,
-    )
+    let all = cfg
+        .graph
+        .node_weights()
+        .map(|x| x.defs())
+        .flatten()
+        .chain(cfg.args.iter().map(|x| x.name.clone()))
+        .collect::<HashSet<_>>();
+    let problem = Analysis {
+        direction: Dir::Forward,
+        top: all,
+        //nothing flows into the entry except the arguments
+        boundary: cfg.args.iter().map(|x| x.name.clone()).collect(),
+        meet: intersection,
+        transfer: |_, block: &T, defined: &HashSet<String>| {
+            defined.union(&block.defs()).cloned().collect()
+        },
+    };
+    solve(cfg, &problem)
+}
+
+/// an assignment to [var]. [index] is the position of the instruction in [block], [None] for a
+/// function argument or a phi, which are assigned at the start of the block
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Definition {
+    pub var: String,
+    pub block: NodeIndex,
+    pub index: Option<usize>,
+}
+
+impl Definition {
+    /// the definition as `var@label[index]`, or `var@label` without an index
+    pub fn name(&self, cfg: &CFG<BasicBlock>) -> String {
+        let label = cfg.graph.node_weight(self.block).unwrap().label.clone();
+        match self.index {
+            Some(i) => format!("{}@{}[{}]", self.var, label.unwrap(), i),
+            None => format!("{}@{}", self.var, label.unwrap()),
+        }
+    }
+}
+
+/// definitions that may reach the start and end of each block without being overwritten
+pub fn reaching_definitions(
+    cfg: &CFG<BasicBlock>,
+) -> (Vec<HashSet<Definition>>, Vec<HashSet<Definition>>) {
+    let start = cfg.start();
+    let problem = Analysis {
+        direction: Dir::Forward,
+        top: HashSet::new(),
+        boundary: HashSet::new(),
+        meet: union,
+        transfer: |node, block: &BasicBlock, reaching: &HashSet<Definition>| {
+            let mut out = reaching.clone();
+            let mut define = |var: String, index: Option<usize>| {
+                out.retain(|x| x.var != var);
+                out.insert(Definition {
+                    var,
+                    block: node,
+                    index,
+                });
+            };
+            if node == start {
+                for arg in &cfg.args {
+                    define(arg.name.clone(), None);
+                }
+            }
+            for (dest, _) in block.phi.values() {
+                define(dest.clone(), None);
+            }
+            for (i, code) in block.instructions.iter().enumerate() {
+                for var in code.defs() {
+                    define(var, Some(i));
+                }
+            }
+            out
+        },
+    };
+    solve(cfg, &problem)
+}
+
+pub fn reaching_definitions_debug(prog: &Program) {
+    for func in &prog.functions {
+        let cfg = graph_from_function(func);
+        let (entry, exit) = reaching_definitions(&cfg);
+        let label = |x: NodeIndex| cfg.graph.node_weight(x).unwrap().label.clone().unwrap();
+        //sorted so the output is stable
+        let show = |defs: &HashSet<Definition>| {
+            let mut defs = defs.iter().map(|x| x.name(&cfg)).collect::<Vec<_>>();
+            defs.sort();
+            defs
+        };
+        println!("@{} Reaching Definitions", func.name);
+        for i in 0..cfg.graph.node_count() {
+            println!("{:?}:", label(NodeIndex::from(i as u32)));
+            println!("Entry: {:?}", show(&entry[i]));
+            println!("Exit: {:?}", show(&exit[i]));
+            println!("");
+        }
+    }
 }
 
 pub fn live_variable_debug(prog: &Program) {
@@ -60,3 +211,46 @@ pub fn live_variable_debug(prog: &Program) {
         }
     }
 }
+
+/// prints the live-in and live-out sets next to every instruction, then the live range of each
+/// variable
+pub fn live_instruction_debug(prog: &Program) {
+    let sorted = |live: &HashSet<String>| {
+        let mut live = live.iter().cloned().collect::<Vec<_>>();
+        live.sort();
+        live
+    };
+    for func in &prog.functions {
+        let cfg = graph_from_function(func);
+        let live = instruction_liveness(&cfg);
+        println!("@{} Liveness Analysis", func.name);
+        let mut pos = 0;
+        for node in cfg.graph.node_indices() {
+            let block = cfg.graph.node_weight(node).unwrap();
+            let facts = &live[node.index()];
+            println!("{:?}:", block.label.clone().unwrap());
+            println!("  Entry: {:?}", sorted(&facts[0]));
+            for (i, code) in block.instructions.iter().enumerate() {
+                println!(
+                    "  {:>3} {:<40} in: {:?} out: {:?}",
+                    pos,
+                    code.to_string(),
+                    sorted(&facts[i]),
+                    sorted(&facts[i + 1])
+                );
+                pos += 1;
+            }
+            println!("  Exit: {:?}", sorted(facts.last().unwrap()));
+        }
+        println!("Live Ranges:");
+        for range in live_ranges(&cfg, &live) {
+            let intervals = range
+                .intervals
+                .iter()
+                .map(|(start, end)| format!("[{}, {}]", start, end))
+                .collect::<Vec<_>>();
+            println!("{}: {}", range.var, intervals.join(" "));
+        }
+        println!("");
+    }
+}
@@ -0,0 +1,135 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+
+use bril_rs::{Code, EffectOps, Instruction, Program, ValueOps};
+use petgraph::algo::tarjan_scc;
+use petgraph::dot::Dot;
+use petgraph::graph::{Graph, NodeIndex};
+
+/// functions of a program with an edge from every caller to each function it calls. calls to
+/// functions that aren't defined in the program (imports) are left out
+#[derive(Debug, Clone)]
+pub struct CallGraph {
+    /// function names, edges are weighted with the number of call sites
+    pub graph: Graph<String, usize>,
+    /// strongly connected components in reverse topological order, callees come before their
+    /// callers
+    pub sccs: Vec<Vec<NodeIndex>>,
+    nodes: HashMap<String, NodeIndex>,
+    //index in [sccs] of each function
+    scc_of: HashMap<NodeIndex, usize>,
+}
+
+impl CallGraph {
+    pub fn node(&self, func: &str) -> Option<NodeIndex> {
+        self.nodes.get(func).copied()
+    }
+
+    /// functions called by [func]
+    pub fn callees(&self, func: &str) -> Vec<&String> {
+        self.node(func).map_or(vec![], |x| {
+            self.graph
+                .neighbors(x)
+                .map(|x| self.graph.node_weight(x).unwrap())
+                .collect()
+        })
+    }
+
+    /// returns true if [func] can call itself, directly or through other functions
+    pub fn is_recursive(&self, func: &str) -> bool {
+        self.node(func).is_some_and(|x| {
+            self.sccs[self.scc_of[&x]].len() > 1 || self.graph.find_edge(x, x).is_some()
+        })
+    }
+
+    /// returns true if [a] and [b] are in the same strongly connected component
+    pub fn same_scc(&self, a: &str, b: &str) -> bool {
+        match (self.node(a), self.node(b)) {
+            (Some(a), Some(b)) => self.scc_of[&a] == self.scc_of[&b],
+            _ => false,
+        }
+    }
+
+    /// function names with callees before callers, functions in the same component are
+    /// ordered arbitrarily
+    pub fn bottom_up(&self) -> Vec<String> {
+        self.sccs
+            .iter()
+            .flatten()
+            .map(|x| self.graph.node_weight(*x).unwrap().clone())
+            .collect()
+    }
+}
+
+/// function called by [code], if it is a call
+pub fn called(code: &Code) -> Option<&String> {
+    match code {
+        Code::Instruction(
+            Instruction::Value {
+                op: ValueOps::Call,
+                funcs,
+                ..
+            }
+            | Instruction::Effect {
+                op: EffectOps::Call,
+                funcs,
+                ..
+            },
+        ) => funcs.first(),
+        _ => None,
+    }
+}
+
+pub fn call_graph(prog: &Program) -> CallGraph {
+    let mut graph = Graph::new();
+    let nodes = prog
+        .functions
+        .iter()
+        .map(|x| (x.name.clone(), graph.add_node(x.name.clone())))
+        .collect::<HashMap<_, _>>();
+    for func in &prog.functions {
+        let caller = nodes[&func.name];
+        for callee in func.instrs.iter().filter_map(called) {
+            if let Some(callee) = nodes.get(callee) {
+                match graph.find_edge(caller, *callee) {
+                    Some(edge) => *graph.edge_weight_mut(edge).unwrap() += 1,
+                    None => {
+                        graph.add_edge(caller, *callee, 1);
+                    }
+                }
+            }
+        }
+    }
+
+    let sccs = tarjan_scc(&graph);
+    let scc_of = sccs
+        .iter()
+        .enumerate()
+        .flat_map(|(i, scc)| scc.iter().map(move |x| (*x, i)))
+        .collect();
+    CallGraph {
+        graph,
+        sccs,
+        nodes,
+        scc_of,
+    }
+}
+
+/// DOT output of [graph] with recursive functions circled twice, edges are labeled with the
+/// number of call sites
+pub fn debug_call_graph_string(graph: &CallGraph) -> String {
+    let node_attrs = |_, (_, func): (NodeIndex, &String)| {
+        if graph.is_recursive(func) {
+            "peripheries = 2".to_string()
+        } else {
+            String::new()
+        }
+    };
+    let mut out = String::new();
+    let _ = writeln!(
+        out,
+        "{}",
+        Dot::with_attr_getters(&graph.graph, &[], &|_, _| String::new(), &node_attrs)
+    );
+    out
+}
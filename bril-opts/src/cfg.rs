@@ -1,4 +1,4 @@
-use std::collections::{HashMap, HashSet, VecDeque};
+use std::collections::{HashMap, HashSet};
 
 use bril_rs::Argument;
 use bril_rs::Code;
@@ -9,10 +9,12 @@ use petgraph::graph::NodeIndex;
 use petgraph::visit::EdgeRef;
 use std::fmt::Write;
 
+use crate::profile::FunctionProfile;
 use crate::utils::code_to_bb_extra_args;
 use crate::utils::BasicBlock;
 use crate::utils::{code_to_bb, CFGNode, CF};
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Dir {
     Forward,
     Backward,
@@ -35,7 +37,15 @@ pub fn graph_from_function(func: &Function) -> CFG<BasicBlock> {
         vartype: HashMap::new(),
     };
 
-    let mut bbs = code_to_bb_extra_args(func.instrs.clone(), &func.args);
+    //the entry label of a function that was already flattened, nothing can jump to it
+    let mut instrs = func.instrs.clone();
+    if let Some(Code::Label { label, .. }) = instrs.first() {
+        if label == "_CFG_ENTRY" {
+            instrs.remove(0);
+        }
+    }
+
+    let mut bbs = code_to_bb_extra_args(instrs, &func.args);
     bbs.insert(0, entry_bb);
     let mut cfg = CFG::new(&bbs);
     cfg.args = func.args.clone();
@@ -159,105 +169,33 @@ impl<T: CFGNode + Clone + std::fmt::Debug + std::fmt::Display> CFG<T> {
     pub fn reverse_postorder(&self) -> Vec<NodeIndex> {
         let mut visited = HashSet::new();
         let mut postorder = Vec::new();
-        let mut work_list = Vec::new();
-        work_list.push(self.start());
-        while let Some(node) = work_list.pop() {
-            if !visited.contains(&node) {
-                visited.insert(node);
-                work_list.push(node);
+        //a node is pushed again as finished once its successors are on the stack, a node may be
+        //pushed by several predecessors but is only expanded the first time
+        let mut work_list = vec![(self.start(), false)];
+        while let Some((node, finished)) = work_list.pop() {
+            if finished {
+                postorder.push(node);
+            } else if visited.insert(node) {
+                work_list.push((node, true));
                 for neighbor in self.graph.neighbors(node) {
                     if !visited.contains(&neighbor) {
-                        work_list.push(neighbor);
+                        work_list.push((neighbor, false));
                     }
                 }
-            } else {
-                postorder.push(node);
             }
         }
         postorder.reverse();
         postorder
     }
 
-    pub fn work_list<U: PartialEq + Clone + std::fmt::Debug>(
-        &self,
-        meet: fn(&U, &U) -> U,                    //meet function
-        transfer: fn(&U, &NodeIndex, &Self) -> U, //F[n]
-        top: U,                                   //initial value of out[n]
-        init: U,                                  //initial value of meet (usually empty set)
-        direction: Dir, //direction of analysis, Dir::Forward or Dir::Backward
-    ) -> (Vec<U>, Vec<U>) {
-        let mut work_list = VecDeque::new();
-        let mut work_list_set = HashSet::new();
-        let mut out_n = vec![top.clone(); self.graph.node_count()];
-        let mut in_n = vec![top.clone(); self.graph.node_count()];
-        let mut visited = Vec::new();
-        let direction = match direction {
-            Dir::Forward => petgraph::Direction::Incoming,
-            Dir::Backward => petgraph::Direction::Outgoing,
-        };
-
-        let reverse_dir = match direction {
-            petgraph::Direction::Incoming => petgraph::Direction::Outgoing,
-            petgraph::Direction::Outgoing => petgraph::Direction::Incoming,
-        };
-
-        for node in self.graph.node_indices() {
-            work_list.push_back(node);
-            work_list_set.insert(node);
-        }
-
-        while !work_list.is_empty() {
-            let node = work_list.pop_front().unwrap();
-            work_list_set.remove(&node);
-            //meet of pred/succ nodes (depending on direction), assumes that init is empty set
-            match direction {
-                //Backward analysis, meet of successors
-                petgraph::Direction::Outgoing => {
-                    let out = self
-                        .graph
-                        .neighbors_directed(node, direction)
-                        .fold(init.clone(), |acc, x| meet(&acc, &in_n[x.index()]));
-                    let new_result = transfer(&out, &node, self);
-                    out_n[node.index()] = out;
-                    //if result changed, add to worklist
-                    if new_result != in_n[node.index()] {
-                        in_n[node.index()] = new_result;
-                        visited.push(node);
-                        for neighbor in self.graph.neighbors_directed(node, reverse_dir) {
-                            if work_list_set.insert(neighbor) {
-                                work_list.push_back(neighbor);
-                            }
-                        }
-                    }
-                }
-                //Forward analysis, meet of predecessors
-                petgraph::Direction::Incoming => {
-                    let in_ = self
-                        .graph
-                        .neighbors_directed(node, direction)
-                        .fold(init.clone(), |acc, x| meet(&acc, &out_n[x.index()]));
-                    let new_result = transfer(&in_, &node, self);
-                    in_n[node.index()] = in_;
-                    //if result changed, add to worklist
-                    if new_result != out_n[node.index()] {
-                        out_n[node.index()] = new_result;
-                        visited.push(node);
-                        for neighbor in self.graph.neighbors_directed(node, reverse_dir) {
-                            if work_list_set.insert(neighbor) {
-                                work_list.push_back(neighbor);
-                            }
-                        }
-                    }
-                }
-            };
-        }
-
-        (in_n, out_n)
-    }
-
+    /// removes blocks unreachable from the entry. unlike [Graph::retain_nodes], the remaining
+    /// blocks keep their relative order, so the entry stays first and the exit stays last
     pub fn delete_unreachable(&mut self) {
         let reachable = self.reachable_from_start();
-        self.graph.retain_nodes(|_, node| reachable.contains(&node));
+        self.graph = self.graph.filter_map(
+            |node, block| reachable.contains(&node).then(|| block.clone()),
+            |_, edge| Some(*edge),
+        );
         self.recompute_defs();
     }
 
@@ -294,12 +232,96 @@ impl CFG<BasicBlock> {
         if start.label.is_none() {
             start.label = Some("_CFG_ENTRY".to_string());
         }
+        let labels = self
+            .graph
+            .node_weights()
+            .filter_map(|x| x.label.clone())
+            .collect::<HashSet<_>>();
         for node in self.graph.node_indices() {
             let bb = self.graph.node_weight_mut(node).unwrap();
             if bb.label.is_none() {
-                bb.label = Some(format!("_CFG_L{}", node.index()));
+                //flattened code keeps the labels of an earlier run, don't reuse them
+                let mut label = format!("_CFG_L{}", node.index());
+                while labels.contains(&label) {
+                    label.push('_');
+                }
+                bb.label = Some(label);
+            }
+        }
+    }
+
+    /// splits every edge [from] -> [to] with a new block that jumps to [to], returns the new block.
+    /// the block is labeled with [prefix] and the first number that isn't in [labels]
+    pub fn split_edge(
+        &mut self,
+        from: NodeIndex,
+        to: NodeIndex,
+        prefix: &str,
+        labels: &mut HashSet<String>,
+    ) -> NodeIndex {
+        self.split_edges(&[from], to, prefix, labels)
+    }
+
+    /// like [CFG::split_edge], but the edges into [to] from every block of [from] go through the
+    /// same new block, such as the preheader of a loop with several entries
+    pub fn split_edges(
+        &mut self,
+        from: &[NodeIndex],
+        to: NodeIndex,
+        prefix: &str,
+        labels: &mut HashSet<String>,
+    ) -> NodeIndex {
+        let target = self.graph.node_weight(to).unwrap();
+        let to_label = target.label.clone().unwrap();
+        let vartype = target.vartype.clone();
+
+        let mut count = 0;
+        while labels.contains(&format!("{}{}", prefix, count)) {
+            count += 1;
+        }
+        let label = format!("{}{}", prefix, count);
+        labels.insert(label.clone());
+
+        let new = self.graph.add_node(BasicBlock {
+            label: Some(label.clone()),
+            phi: HashMap::new(),
+            instructions: vec![Code::Instruction(bril_rs::Instruction::Effect {
+                args: vec![],
+                funcs: vec![],
+                labels: vec![to_label.clone()],
+                op: bril_rs::EffectOps::Jump,
+                pos: None,
+            })],
+            defs: HashSet::new(),
+            uses: HashSet::new(),
+            vartype,
+        });
+
+        for from in from {
+            if let Some(Code::Instruction(bril_rs::Instruction::Effect {
+                labels: targets, ..
+            })) = self
+                .graph
+                .node_weight_mut(*from)
+                .unwrap()
+                .instructions
+                .last_mut()
+            {
+                for target in targets.iter_mut() {
+                    if *target == to_label {
+                        *target = label.clone();
+                    }
+                }
+            }
+
+            while let Some(edge) = self.graph.find_edge(*from, to) {
+                let weight = *self.graph.edge_weight(edge).unwrap();
+                self.graph.remove_edge(edge);
+                self.graph.add_edge(*from, new, weight);
             }
         }
+        self.graph.add_edge(new, to, false);
+        new
     }
 
     /// performs trace analysis and block reordering to get a vector of LIRNodes
@@ -337,6 +359,115 @@ impl CFG<BasicBlock> {
         }
 
         let jumps_to_insert = Self::reorder_traces(&mut traces, &self.graph);
+
+        //restore removed edges
+        for edge in removed_edges {
+            self.graph.add_edge(edge.0, edge.1, true);
+        }
+        self.emit_traces(traces, jumps_to_insert)
+    }
+
+    /// lays the blocks out by [profile], which counts how often each edge ran, keyed by the
+    /// labels of its blocks. edges are taken hottest first, joining two chains of blocks when
+    /// the edge leaves the end of one and enters the start of the other, so a hot edge falls
+    /// through instead of jumping. the chain of the entry goes first, then the chain that
+    /// starts with the hottest successor of the last one placed, or else the hottest chain left.
+    /// edges that never ran keep the preference of [CFG::flatten] for false and jump edges
+    pub fn flatten_with_profile(&mut self, profile: &FunctionProfile) -> Vec<Code> {
+        let label = |x: NodeIndex| self.graph.node_weight(x).unwrap().label.clone();
+        let count = |from: NodeIndex, to: NodeIndex| match (label(from), label(to)) {
+            (Some(from), Some(to)) => profile.count(&from, &to),
+            _ => 0,
+        };
+        let mut edges = self
+            .graph
+            .edge_references()
+            .map(|x| (x.source(), x.target(), *x.weight()))
+            .collect::<Vec<_>>();
+        edges.sort_by_key(|(from, to, taken)| {
+            (std::cmp::Reverse(count(*from, *to)), *taken, *from, *to)
+        });
+        let heat = |node: NodeIndex| -> u64 {
+            self.graph
+                .neighbors_directed(node, petgraph::Direction::Incoming)
+                .map(|x| count(x, node))
+                .sum()
+        };
+
+        //chain of each block, and the blocks of each chain
+        let mut chain_of = self
+            .graph
+            .node_indices()
+            .map(|x| (x, x.index()))
+            .collect::<HashMap<_, _>>();
+        let mut chains = self
+            .graph
+            .node_indices()
+            .map(|x| vec![x])
+            .collect::<Vec<_>>();
+        for (from, to, _) in edges {
+            let (a, b) = (chain_of[&from], chain_of[&to]);
+            if a == b
+                || to == self.start()
+                || *chains[a].last().unwrap() != from
+                || chains[b][0] != to
+            {
+                continue;
+            }
+            let moved = std::mem::take(&mut chains[b]);
+            for node in &moved {
+                chain_of.insert(*node, a);
+            }
+            chains[a].extend(moved);
+        }
+
+        let mut traces = vec![];
+        let mut next = Some(chain_of[&self.start()]);
+        while let Some(chain) = next {
+            let tail = *chains[chain].last().unwrap();
+            traces.push(std::mem::take(&mut chains[chain]));
+            let mut successors = self.graph.neighbors(tail).collect::<Vec<_>>();
+            successors.sort_by_key(|x| (std::cmp::Reverse(count(tail, *x)), x.index()));
+            next = successors
+                .into_iter()
+                .find(|x| chains[chain_of[x]].first() == Some(x))
+                .map(|x| chain_of[&x])
+                .or_else(|| {
+                    (0..chains.len())
+                        .filter(|x| !chains[*x].is_empty())
+                        .max_by_key(|x| {
+                            let head = chains[*x][0];
+                            (heat(head), std::cmp::Reverse(head.index()))
+                        })
+                });
+        }
+
+        //a trace that ends by falling through or jumping goes on with a jump, which is dropped
+        //again if its target comes right after it
+        let jumps_to_insert = traces
+            .iter()
+            .map(|trace| {
+                let last = *trace.last().unwrap();
+                match self.graph.node_weight(last).unwrap().control_flow() {
+                    CF::Normal | CF::Label(_) | CF::Jump(_) => self
+                        .graph
+                        .neighbors(last)
+                        .next()
+                        .map(|x| x.index() as isize),
+                    _ => None,
+                }
+            })
+            .collect();
+        self.emit_traces(traces, jumps_to_insert)
+    }
+
+    /// the code of [traces] in order, each followed by a jump to the block in
+    /// [jumps_to_insert] unless it already ends with one
+    fn emit_traces(
+        &mut self,
+        traces: Vec<Vec<NodeIndex>>,
+        jumps_to_insert: Vec<Option<isize>>,
+    ) -> Vec<Code> {
         let mut code = traces
             .into_iter()
             .enumerate()
@@ -344,7 +475,7 @@ impl CFG<BasicBlock> {
                 let mut extract_node_weights = x
                     .into_iter()
                     .map(|x| {
-                        if x == self.exit() {
+                        if self.falls_off_end(x) {
                             let mut block = self.graph.node_weight(x).unwrap().clone();
                             block.instructions.push(Code::Instruction(
                                 bril_rs::Instruction::Effect {
@@ -424,17 +555,35 @@ impl CFG<BasicBlock> {
         //     .collect::<HashSet<_>>();
         // let mut removed_extra_labels = code;
 
-        //restore removed edges
-        for edge in removed_edges {
-            self.graph.add_edge(edge.0, edge.1, true);
-        }
         code.push(Code::Label {
             label: "_CFG_EXIT".into(),
             pos: None,
         });
+
+        //a jump to the label right after it falls through anyway
+        let redundant = code
+            .windows(2)
+            .map(|x| match (x[0].control_flow(), &x[1]) {
+                (CF::Jump(target), Code::Label { label, .. }) => target == *label,
+                _ => false,
+            })
+            .collect::<Vec<_>>();
+        let mut i = 0;
+        code.retain(|_| {
+            i += 1;
+            !redundant.get(i - 1).copied().unwrap_or(false)
+        });
         code
     }
 
+    /// true if [node] ends the function by running past its last instruction
+    fn falls_off_end(&self, node: NodeIndex) -> bool {
+        match self.graph.node_weight(node).unwrap().control_flow() {
+            CF::Normal | CF::Label(_) => self.graph.neighbors(node).next().is_none(),
+            _ => false,
+        }
+    }
+
     fn heuristic(
         nodes: petgraph::graph::NodeIndices,
         marked: &HashSet<NodeIndex>,
@@ -0,0 +1,173 @@
+use std::collections::{BTreeSet, HashSet};
+use std::fmt::{Debug, Display};
+
+use bril_rs::Code;
+use petgraph::graph::NodeIndex;
+use petgraph::Direction;
+
+use crate::cfg::{Dir, CFG};
+use crate::utils::{BasicBlock, CFGNode};
+
+/// a dataflow problem over the blocks of a [CFG]. facts form a lattice where [Dataflow::meet]
+/// moves down from [Dataflow::top]
+pub trait Dataflow<T> {
+    type Fact: Clone + PartialEq;
+
+    fn direction(&self) -> Dir;
+    /// initial fact of every block, the meet of nothing
+    fn top(&self) -> Self::Fact;
+    /// fact flowing into the entry of a forward problem, or out of the exits of a backward one
+    fn boundary(&self) -> Self::Fact;
+    /// combines [other] into [fact]
+    fn meet(&self, fact: &mut Self::Fact, other: &Self::Fact);
+    /// fact on the other side of [block] given the fact on the side the analysis comes from
+    fn transfer(&self, node: NodeIndex, block: &T, fact: &Self::Fact) -> Self::Fact;
+}
+
+/// a [Dataflow] problem made of closures, which can capture whatever the analysis needs
+pub struct Analysis<U, M, F> {
+    pub direction: Dir,
+    pub top: U,
+    pub boundary: U,
+    pub meet: M,
+    pub transfer: F,
+}
+
+impl<T, U, M, F> Dataflow<T> for Analysis<U, M, F>
+where
+    U: Clone + PartialEq,
+    M: Fn(&mut U, &U),
+    F: Fn(NodeIndex, &T, &U) -> U,
+{
+    type Fact = U;
+
+    fn direction(&self) -> Dir {
+        self.direction
+    }
+
+    fn top(&self) -> U {
+        self.top.clone()
+    }
+
+    fn boundary(&self) -> U {
+        self.boundary.clone()
+    }
+
+    fn meet(&self, fact: &mut U, other: &U) {
+        (self.meet)(fact, other)
+    }
+
+    fn transfer(&self, node: NodeIndex, block: &T, fact: &U) -> U {
+        (self.transfer)(node, block, fact)
+    }
+}
+
+/// meet for problems over sets where a fact holds if it holds on any path
+pub fn union<V: Eq + std::hash::Hash + Clone>(fact: &mut HashSet<V>, other: &HashSet<V>) {
+    fact.extend(other.iter().cloned());
+}
+
+/// meet for problems over sets where a fact holds if it holds on every path
+pub fn intersection<V: Eq + std::hash::Hash>(fact: &mut HashSet<V>, other: &HashSet<V>) {
+    fact.retain(|x| other.contains(x));
+}
+
+/// solves [problem] on [cfg], returns the facts at the start and end of every block. blocks are
+/// visited in reverse postorder (postorder for backward problems) and only revisited when a
+/// neighbor's fact changes. unreachable blocks are visited last
+pub fn solve<T, D>(cfg: &CFG<T>, problem: &D) -> (Vec<D::Fact>, Vec<D::Fact>)
+where
+    T: CFGNode + Clone + Debug + Display,
+    D: Dataflow<T>,
+{
+    let n = cfg.graph.node_count();
+    let forward = problem.direction() == Dir::Forward;
+    let (from, to) = match forward {
+        true => (Direction::Incoming, Direction::Outgoing),
+        false => (Direction::Outgoing, Direction::Incoming),
+    };
+
+    let mut order = cfg.reverse_postorder();
+    let reachable = order.iter().copied().collect::<HashSet<_>>();
+    order.extend(cfg.graph.node_indices().filter(|x| !reachable.contains(x)));
+    if !forward {
+        order.reverse();
+    }
+    let mut rank = vec![0; n];
+    for (i, node) in order.iter().enumerate() {
+        rank[node.index()] = i;
+    }
+
+    //facts on the side the analysis comes from and the side it goes to
+    let mut before = vec![problem.top(); n];
+    let mut after = vec![problem.top(); n];
+    let mut work_list = (0..n).collect::<BTreeSet<_>>();
+    while let Some(i) = work_list.pop_first() {
+        let node = order[i];
+        let boundary = match forward {
+            true => node == cfg.start(),
+            false => cfg.graph.neighbors_directed(node, from).next().is_none(),
+        };
+        let mut fact = boundary.then(|| problem.boundary());
+        for neighbor in cfg.graph.neighbors_directed(node, from) {
+            match &mut fact {
+                Some(fact) => problem.meet(fact, &after[neighbor.index()]),
+                None => fact = Some(after[neighbor.index()].clone()),
+            }
+        }
+        let fact = fact.unwrap_or_else(|| problem.top());
+
+        let result = problem.transfer(node, cfg.graph.node_weight(node).unwrap(), &fact);
+        before[node.index()] = fact;
+        if result != after[node.index()] {
+            after[node.index()] = result;
+            for neighbor in cfg.graph.neighbors_directed(node, to) {
+                work_list.insert(rank[neighbor.index()]);
+            }
+        }
+    }
+
+    match forward {
+        true => (before, after),
+        false => (after, before),
+    }
+}
+
+/// facts at every point inside the blocks of [cfg], from the block facts in [solution] found by
+/// [solve]. `facts[block][i]` holds just before instruction `i` and `facts[block][len]` at the
+/// end of the block. [step] is the effect of a single instruction, phis aren't visited
+pub fn instruction_facts<D, S>(
+    cfg: &CFG<BasicBlock>,
+    problem: &D,
+    solution: &(Vec<D::Fact>, Vec<D::Fact>),
+    step: S,
+) -> Vec<Vec<D::Fact>>
+where
+    D: Dataflow<BasicBlock>,
+    S: Fn(NodeIndex, usize, &Code, &D::Fact) -> D::Fact,
+{
+    let (entry, exit) = solution;
+    cfg.graph
+        .node_indices()
+        .map(|node| {
+            let instructions = &cfg.graph.node_weight(node).unwrap().instructions;
+            match problem.direction() {
+                Dir::Forward => {
+                    let mut facts = vec![entry[node.index()].clone()];
+                    for (i, code) in instructions.iter().enumerate() {
+                        facts.push(step(node, i, code, &facts[i]));
+                    }
+                    facts
+                }
+                Dir::Backward => {
+                    let mut facts = vec![exit[node.index()].clone()];
+                    for (i, code) in instructions.iter().enumerate().rev() {
+                        facts.push(step(node, i, code, facts.last().unwrap()));
+                    }
+                    facts.reverse();
+                    facts
+                }
+            }
+        })
+        .collect()
+}
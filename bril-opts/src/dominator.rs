@@ -1,7 +1,8 @@
 use crate::dominator;
 use crate::utils::{BasicBlock, CFGNode};
-use petgraph::algo::dominators::Dominators;
-use petgraph::{graph::NodeIndex, Direction};
+use petgraph::algo::dominators::{simple_fast, Dominators};
+use petgraph::visit::{Dfs, EdgeRef};
+use petgraph::{graph::NodeIndex, Direction, Graph};
 use std::collections::HashSet;
 use std::vec;
 
@@ -117,3 +118,74 @@ pub fn dom_tree(
     }
     dom_tree
 }
+
+/// post-dominators of [cfg], the dominators of the reversed CFG. blocks keep their index and the
+/// returned node, numbered after every block, is a virtual exit that follows each block without
+/// successors. blocks that can't reach one (infinite loops) follow the exit too, so it
+/// post-dominates every block
+pub fn post_dominator_analysis<T: Clone + std::fmt::Debug + std::fmt::Display + CFGNode>(
+    cfg: &CFG<T>,
+) -> (Dominators<NodeIndex>, NodeIndex) {
+    let mut reversed = Graph::<(), ()>::new();
+    for _ in cfg.graph.node_indices() {
+        reversed.add_node(());
+    }
+    let exit = reversed.add_node(());
+    for edge in cfg.graph.edge_references() {
+        reversed.add_edge(edge.target(), edge.source(), ());
+    }
+    for node in cfg.graph.node_indices() {
+        if cfg.graph.neighbors(node).next().is_none() {
+            reversed.add_edge(exit, node, ());
+        }
+    }
+    //a loop that never exits is entered from its last block, which is usually its bottom
+    loop {
+        let mut reached = HashSet::new();
+        let mut dfs = Dfs::new(&reversed, exit);
+        while let Some(node) = dfs.next(&reversed) {
+            reached.insert(node);
+        }
+        match cfg
+            .graph
+            .node_indices()
+            .rev()
+            .find(|x| !reached.contains(x))
+        {
+            Some(node) => {
+                reversed.add_edge(exit, node, ());
+            }
+            None => break,
+        }
+    }
+    (simple_fast(&reversed, exit), exit)
+}
+
+/// control dependence graph of [cfg] from the [post_dominators] of [post_dominator_analysis].
+/// nodes are the blocks of [cfg], with an edge from a block to every block whose execution
+/// depends on which successor it picks
+pub fn control_dependence<T: Clone + std::fmt::Debug + std::fmt::Display + CFGNode>(
+    cfg: &CFG<T>,
+    post_dominators: &Dominators<NodeIndex>,
+) -> Graph<(), ()> {
+    let mut cdg = Graph::<(), ()>::new();
+    for _ in cfg.graph.node_indices() {
+        cdg.add_node(());
+    }
+    for edge in cfg.graph.edge_references() {
+        let (from, to) = (edge.source(), edge.target());
+        let stop = post_dominators.immediate_dominator(from);
+        //every block on the way up the post-dominator tree from the successor runs because of
+        //this edge, until reaching the block that runs either way
+        let mut runner = Some(to);
+        while let Some(node) =
+            runner.filter(|x| Some(*x) != stop && x.index() < cfg.graph.node_count())
+        {
+            if cdg.find_edge(from, node).is_none() {
+                cdg.add_edge(from, node, ());
+            }
+            runner = post_dominators.immediate_dominator(node);
+        }
+    }
+    cdg
+}
@@ -0,0 +1,182 @@
+use std::collections::HashSet;
+use std::fmt::Write;
+
+use petgraph::graph::NodeIndex;
+use petgraph::visit::EdgeRef;
+
+use crate::analysis::{live_variable_analysis, reaching_definitions, Definition};
+use crate::cfg::CFG;
+use crate::dominator::dominator_analyis;
+use crate::loops::find_loops;
+use crate::utils::{BasicBlock, CFGNode, CF};
+
+/// a fact of every block, drawn under its instructions as `name: values`
+#[derive(Debug, Clone)]
+pub struct Facts {
+    pub name: String,
+    /// values of each block, by node index
+    pub values: Vec<Vec<String>>,
+}
+
+impl Facts {
+    /// the sets of an analysis, such as one side of the result of [crate::dataflow::solve],
+    /// sorted
+    pub fn from_sets<T: ToString>(name: &str, sets: &[HashSet<T>]) -> Self {
+        let values = sets
+            .iter()
+            .map(|set| {
+                let mut values = set.iter().map(|x| x.to_string()).collect::<Vec<_>>();
+                values.sort();
+                values
+            })
+            .collect();
+        Facts {
+            name: name.to_string(),
+            values,
+        }
+    }
+
+    /// live variables at the start and end of each block
+    pub fn liveness(cfg: &CFG<BasicBlock>) -> Vec<Self> {
+        let (entry, exit) = live_variable_analysis(cfg);
+        vec![
+            Facts::from_sets("live in", &entry),
+            Facts::from_sets("live out", &exit),
+        ]
+    }
+
+    /// definitions reaching the start and end of each block, named as in
+    /// [crate::analysis::Definition::name]
+    pub fn reaching(cfg: &CFG<BasicBlock>) -> Vec<Self> {
+        let (entry, exit) = reaching_definitions(cfg);
+        let names = |sets: Vec<HashSet<Definition>>| {
+            sets.iter()
+                .map(|x| x.iter().map(|x| x.name(cfg)).collect())
+                .collect::<Vec<HashSet<_>>>()
+        };
+        vec![
+            Facts::from_sets("reaching in", &names(entry)),
+            Facts::from_sets("reaching out", &names(exit)),
+        ]
+    }
+}
+
+/// what [render] draws on top of the CFG
+#[derive(Debug, Clone, Default)]
+pub struct DotOptions {
+    /// dashed blue edges from each block to the blocks it immediately dominates
+    pub dom_tree: bool,
+    /// loop headers circled twice, back edges drawn bold and blocks tagged with their depth
+    pub loops: bool,
+    pub facts: Vec<Facts>,
+}
+
+fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// quotes [s] as a DOT string
+fn quote(s: &str) -> String {
+    format!("\"{}\"", escape(s))
+}
+
+/// the label of a node: the block's label, its instructions and its facts, each line left
+/// aligned
+fn node_label(block: &BasicBlock, facts: &[(&str, &[String])]) -> String {
+    let mut lines = vec![format!("{}:", block.label.clone().unwrap_or_default())];
+    for code in block
+        .as_code_block()
+        .iter()
+        .skip(block.label.is_some() as usize)
+    {
+        lines.push(code.to_string());
+    }
+    if !facts.is_empty() {
+        lines.push(String::new());
+    }
+    for (name, values) in facts {
+        lines.push(format!("{}: {}", name, values.join(", ")));
+    }
+    let mut label = String::new();
+    for line in lines {
+        let _ = write!(label, "{}\\l", escape(&line));
+    }
+    format!("\"{}\"", label)
+}
+
+/// [cfg] of the function [name] as a `subgraph cluster`, see [render]
+pub fn cluster(name: &str, cfg: &CFG<BasicBlock>, options: &DotOptions) -> String {
+    let id = |x: NodeIndex| quote(&format!("{}.{}", name, x.index()));
+    let forest = find_loops(cfg);
+    let mut out = String::new();
+    let _ = writeln!(out, "  subgraph {} {{", quote(&format!("cluster_{}", name)));
+    let _ = writeln!(out, "    label = {};", quote(&format!("@{}", name)));
+    let _ = writeln!(out, "    node [shape = box, fontname = \"monospace\"];");
+
+    for (node, block) in cfg.graph.node_indices().zip(cfg.graph.node_weights()) {
+        let facts = options
+            .facts
+            .iter()
+            .map(|x| (x.name.as_str(), x.values[node.index()].as_slice()))
+            .collect::<Vec<_>>();
+        let mut attrs = vec![format!("label = {}", node_label(block, &facts))];
+        if options.loops {
+            if forest.loops.iter().any(|x| x.header == node) {
+                attrs.push("peripheries = 2".to_string());
+            }
+            if forest.depth(node) > 0 {
+                attrs.push(format!("xlabel = \"depth {}\"", forest.depth(node)));
+            }
+        }
+        let _ = writeln!(out, "    {} [{}];", id(node), attrs.join(", "));
+    }
+
+    for edge in cfg.graph.edge_references() {
+        let (from, to) = (edge.source(), edge.target());
+        let branch = matches!(
+            cfg.graph.node_weight(from).unwrap().control_flow(),
+            CF::Branch(..)
+        );
+        let mut attrs = match (branch, *edge.weight()) {
+            (true, true) => vec!["label = \"true\"", "color = \"forestgreen\""],
+            (true, false) => vec!["label = \"false\"", "color = \"crimson\""],
+            (false, _) => vec![],
+        };
+        if options.loops && forest.is_back_edge(from, to) {
+            attrs.push("style = bold");
+        }
+        let _ = match attrs.is_empty() {
+            true => writeln!(out, "    {} -> {};", id(from), id(to)),
+            false => writeln!(
+                out,
+                "    {} -> {} [{}];",
+                id(from),
+                id(to),
+                attrs.join(", ")
+            ),
+        };
+    }
+
+    if options.dom_tree {
+        let (dominators, _) = dominator_analyis(cfg);
+        for node in cfg.graph.node_indices() {
+            if let Some(idom) = dominators.immediate_dominator(node) {
+                let _ = writeln!(
+                    out,
+                    "    {} -> {} [style = dashed, color = \"blue\", constraint = false];",
+                    id(idom),
+                    id(node)
+                );
+            }
+        }
+    }
+    let _ = writeln!(out, "  }}");
+    out
+}
+
+/// a DOT graph of [cfg] with one cluster for the function [name]. nodes show the label and
+/// instructions of each block, the true and false edges of a `br` are labeled and colored, and
+/// [options] adds the overlays
+pub fn render(name: &str, cfg: &CFG<BasicBlock>, options: &DotOptions) -> String {
+    format!("digraph {{\n{}}}\n", cluster(name, cfg, options))
+}
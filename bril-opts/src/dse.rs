@@ -0,0 +1,289 @@
+use std::collections::HashSet;
+
+use bril_rs::{Code, EffectOps, Function, Instruction, Program, Type, ValueOps};
+use petgraph::graph::NodeIndex;
+
+use crate::alias::{points_to, Loc, Offset, PointsTo, Targets};
+use crate::cfg::{graph_from_function, Dir, CFG};
+use crate::dataflow::{instruction_facts, intersection, solve, Analysis};
+use crate::utils::{BasicBlock, CFGNode};
+
+/// dead store elimination, removes stores that are overwritten or freed before they are read
+/// and allocations whose memory is never read
+pub fn dse(prog: Program) -> Program {
+    Program {
+        functions: prog.functions.into_iter().map(dse_function).collect(),
+        imports: prog.imports,
+    }
+}
+
+fn dse_function(func: Function) -> Function {
+    let mut cfg = graph_from_function(&func);
+    //flattening reorders blocks, so leave functions without dead stores alone
+    if !dse_cfg(&mut cfg) {
+        return func;
+    }
+    Function {
+        instrs: cfg.flatten(),
+        ..func
+    }
+}
+
+/// memory that won't be read before it is overwritten or freed
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Dead {
+    /// the memory a variable points to
+    Var(String),
+    /// every object allocated by a site
+    Site(Loc),
+}
+
+impl Dead {
+    fn targets(&self, memory: &PointsTo, node: NodeIndex, index: usize) -> Targets {
+        match self {
+            Dead::Var(var) => memory.targets(node, index, var),
+            Dead::Site(loc) => Targets::from([(loc.clone(), Offset::Any)]),
+        }
+    }
+}
+
+/// dead memory before instruction [index] of [node] given the dead memory after it
+fn step(
+    memory: &PointsTo,
+    node: NodeIndex,
+    index: usize,
+    code: &Code,
+    dead: &HashSet<Dead>,
+) -> HashSet<Dead> {
+    let mut out = dead.clone();
+    //a variable names a different pointer before it is assigned
+    for var in code.defs() {
+        out.remove(&Dead::Var(var));
+    }
+    let read = |out: &mut HashSet<Dead>, ptr: &String| {
+        let targets = memory.targets(node, index, ptr);
+        out.retain(|x| !memory.may_alias(&x.targets(memory, node, index), &targets));
+    };
+    match code {
+        Code::Instruction(Instruction::Value {
+            op: ValueOps::Load,
+            args,
+            ..
+        }) => read(&mut out, &args[0]),
+        Code::Instruction(Instruction::Value {
+            op: ValueOps::Call, ..
+        })
+        | Code::Instruction(Instruction::Effect {
+            op: EffectOps::Call,
+            ..
+        }) => out.retain(|x| !memory.escapes(&x.targets(memory, node, index))),
+        Code::Instruction(Instruction::Effect {
+            op: EffectOps::Store,
+            args,
+            ..
+        }) => {
+            out.insert(Dead::Var(args[0].clone()));
+        }
+        Code::Instruction(Instruction::Effect {
+            op: EffectOps::Free,
+            args,
+            ..
+        }) => {
+            //a site in a loop may have older objects that are still read
+            let targets = memory.targets(node, index, &args[0]);
+            if let (1, Some(loc)) = (targets.len(), targets.keys().next()) {
+                if memory.allocates_once(loc) {
+                    out.insert(Dead::Site(loc.clone()));
+                }
+            }
+        }
+        _ => (),
+    }
+    out
+}
+
+/// returns true if the store to [ptr] at instruction [index] of [node] is never read, given the
+/// dead memory after it
+fn is_dead_store(
+    memory: &PointsTo,
+    node: NodeIndex,
+    index: usize,
+    ptr: &String,
+    dead: &HashSet<Dead>,
+) -> bool {
+    let targets = memory.targets(node, index, ptr);
+    let sites = targets
+        .keys()
+        .all(|x| *x != Loc::Unknown && dead.contains(&Dead::Site(x.clone())));
+    sites
+        || dead.iter().any(|x| match x {
+            Dead::Var(var) => {
+                var == ptr || memory.must_alias(&memory.targets(node, index, var), &targets)
+            }
+            Dead::Site(_) => false,
+        })
+}
+
+/// every instruction of [cfg] with its position
+fn instructions(cfg: &CFG<BasicBlock>) -> impl Iterator<Item = (NodeIndex, usize, &Code)> {
+    cfg.graph.node_indices().flat_map(move |node| {
+        let block = cfg.graph.node_weight(node).unwrap();
+        block
+            .instructions
+            .iter()
+            .enumerate()
+            .map(move |(i, code)| (node, i, code))
+    })
+}
+
+/// runs dead store elimination on [cfg], returns true if anything was removed
+pub fn dse_cfg(cfg: &mut CFG<BasicBlock>) -> bool {
+    let memory = points_to(cfg);
+    let sites = instructions(cfg)
+        .filter(|(_, _, code)| {
+            matches!(
+                code,
+                Code::Instruction(Instruction::Value {
+                    op: ValueOps::Alloc,
+                    ..
+                })
+            )
+        })
+        .map(|(node, i, _)| Dead::Site(Loc::Alloc(node, i)))
+        .collect::<HashSet<_>>();
+
+    //allocations that nothing reads, directly or through a call, are dead everywhere
+    let unread = sites
+        .iter()
+        .filter(|site| {
+            let site = match site {
+                Dead::Site(loc) => Targets::from([(loc.clone(), Offset::Any)]),
+                Dead::Var(_) => unreachable!(),
+            };
+            let loaded = instructions(cfg).any(|(node, i, code)| match code {
+                Code::Instruction(Instruction::Value {
+                    op: ValueOps::Load,
+                    args,
+                    ..
+                }) => memory.may_alias(&memory.targets(node, i, &args[0]), &site),
+                _ => false,
+            });
+            !loaded && !memory.escapes(&site)
+        })
+        .cloned()
+        .collect::<HashSet<_>>();
+
+    let universe = instructions(cfg)
+        .filter_map(|(_, _, code)| match code {
+            Code::Instruction(Instruction::Effect {
+                op: EffectOps::Store,
+                args,
+                ..
+            }) => Some(Dead::Var(args[0].clone())),
+            _ => None,
+        })
+        .chain(sites)
+        .collect::<HashSet<_>>();
+    let problem = Analysis {
+        direction: Dir::Backward,
+        top: universe,
+        boundary: HashSet::new(),
+        meet: intersection,
+        transfer: |node: NodeIndex, block: &BasicBlock, dead: &HashSet<Dead>| {
+            let mut dead = dead.clone();
+            for (i, code) in block.instructions.iter().enumerate().rev() {
+                dead = step(&memory, node, i, code, &dead);
+            }
+            dead
+        },
+    };
+    let solution = solve(cfg, &problem);
+    let dead = instruction_facts(cfg, &problem, &solution, |node, i, code, dead| {
+        step(&memory, node, i, code, dead)
+    });
+
+    let mut removed = instructions(cfg)
+        .filter(|(node, i, code)| match code {
+            Code::Instruction(Instruction::Effect {
+                op: EffectOps::Store,
+                args,
+                ..
+            }) => {
+                let mut after = dead[node.index()][i + 1].clone();
+                after.extend(unread.iter().cloned());
+                is_dead_store(&memory, *node, *i, &args[0], &after)
+            }
+            Code::Instruction(Instruction::Effect {
+                op: EffectOps::Free,
+                args,
+                ..
+            }) => memory
+                .targets(*node, *i, &args[0])
+                .keys()
+                .all(|x| unread.contains(&Dead::Site(x.clone()))),
+            _ => false,
+        })
+        .map(|(node, i, _)| (node, i))
+        .collect::<HashSet<_>>();
+    remove_unused_pointers(cfg, &unread, &mut removed);
+    if removed.is_empty() {
+        return false;
+    }
+
+    for node in cfg.graph.node_indices().collect::<Vec<_>>() {
+        let block = cfg.graph.node_weight_mut(node).unwrap();
+        let mut i = 0;
+        block.instructions.retain(|_| {
+            i += 1;
+            !removed.contains(&(node, i - 1))
+        });
+        (block.uses, block.defs) = block.uses_and_defs();
+    }
+    cfg.recompute_defs();
+    true
+}
+
+/// adds to [removed] the unread allocations, and the pointer arithmetic on them, whose
+/// pointers are only used by instructions already in [removed]
+fn remove_unused_pointers(
+    cfg: &CFG<BasicBlock>,
+    unread: &HashSet<Dead>,
+    removed: &mut HashSet<(NodeIndex, usize)>,
+) {
+    loop {
+        let used = instructions(cfg)
+            .filter(|(node, i, _)| !removed.contains(&(*node, *i)))
+            .flat_map(|(_, _, code)| code.uses())
+            .chain(
+                cfg.graph
+                    .node_weights()
+                    .flat_map(|x| x.phi.values())
+                    .flat_map(|(_, sources)| sources.iter().map(|(var, _)| var.clone())),
+            )
+            .collect::<HashSet<_>>();
+        let unused = instructions(cfg)
+            .filter(|(node, i, code)| {
+                let removable = match code {
+                    Code::Instruction(Instruction::Value {
+                        op: ValueOps::Alloc,
+                        ..
+                    }) => unread.contains(&Dead::Site(Loc::Alloc(*node, *i))),
+                    Code::Instruction(Instruction::Value {
+                        op: ValueOps::PtrAdd | ValueOps::Id,
+                        op_type: Type::Pointer(_),
+                        ..
+                    }) => true,
+                    _ => false,
+                };
+                removable
+                    && !removed.contains(&(*node, *i))
+                    && code.defs().iter().all(|x| !used.contains(x))
+            })
+            .map(|(node, i, _)| (node, i))
+            .collect::<Vec<_>>();
+        if unused.is_empty() {
+            break;
+        }
+        removed.extend(unused);
+    }
+}
@@ -0,0 +1,175 @@
+use std::collections::HashMap;
+
+use bril_rs::{Code, EffectOps, Instruction, Program, ValueOps};
+use petgraph::algo::is_cyclic_directed;
+
+use crate::callgraph::{call_graph, called};
+use crate::cfg::graph_from_function;
+
+/// what running an instruction or a function can do besides computing its result
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Effects {
+    pub reads: bool,
+    pub writes: bool,
+    pub prints: bool,
+    /// allocates or frees memory
+    pub allocates: bool,
+    pub may_diverge: bool,
+}
+
+impl Effects {
+    pub const NONE: Effects = Effects {
+        reads: false,
+        writes: false,
+        prints: false,
+        allocates: false,
+        may_diverge: false,
+    };
+
+    pub const ALL: Effects = Effects {
+        reads: true,
+        writes: true,
+        prints: true,
+        allocates: true,
+        may_diverge: true,
+    };
+
+    pub fn union(self, other: Effects) -> Effects {
+        Effects {
+            reads: self.reads || other.reads,
+            writes: self.writes || other.writes,
+            prints: self.prints || other.prints,
+            allocates: self.allocates || other.allocates,
+            may_diverge: self.may_diverge || other.may_diverge,
+        }
+    }
+
+    /// returns true if the result only depends on the arguments and nothing else happens, so
+    /// the same computation can be reused
+    pub fn is_pure(&self) -> bool {
+        *self == Effects::NONE
+    }
+
+    /// returns true if nothing observable happens besides reading memory, so the instruction
+    /// can be removed when its result is unused
+    pub fn is_removable(&self) -> bool {
+        !(self.writes || self.prints || self.allocates || self.may_diverge)
+    }
+}
+
+/// effects of [op], a call has the effects of whatever it calls
+pub fn value_op_effects(op: &ValueOps) -> Effects {
+    match op {
+        ValueOps::Load => Effects {
+            reads: true,
+            ..Effects::NONE
+        },
+        ValueOps::Alloc => Effects {
+            allocates: true,
+            ..Effects::NONE
+        },
+        ValueOps::Call => Effects::ALL,
+        _ => Effects::NONE,
+    }
+}
+
+/// effects of [op], a call has the effects of whatever it calls
+pub fn effect_op_effects(op: &EffectOps) -> Effects {
+    match op {
+        EffectOps::Jump | EffectOps::Branch | EffectOps::Return | EffectOps::Nop => Effects::NONE,
+        EffectOps::Print => Effects {
+            prints: true,
+            ..Effects::NONE
+        },
+        EffectOps::Store => Effects {
+            writes: true,
+            ..Effects::NONE
+        },
+        EffectOps::Free => Effects {
+            writes: true,
+            allocates: true,
+            ..Effects::NONE
+        },
+        _ => Effects::ALL,
+    }
+}
+
+/// effects of every function of a program, including those of the functions it calls
+#[derive(Debug, Clone)]
+pub struct Summaries {
+    pub functions: HashMap<String, Effects>,
+}
+
+impl Summaries {
+    /// effects of calling [func], anything can happen in a function that isn't in the program
+    pub fn function(&self, func: &str) -> Effects {
+        self.functions.get(func).copied().unwrap_or(Effects::ALL)
+    }
+
+    pub fn instruction(&self, ins: &Instruction) -> Effects {
+        match ins {
+            Instruction::Constant { .. } => Effects::NONE,
+            Instruction::Value {
+                op: ValueOps::Call,
+                funcs,
+                ..
+            }
+            | Instruction::Effect {
+                op: EffectOps::Call,
+                funcs,
+                ..
+            } => funcs.first().map_or(Effects::ALL, |x| self.function(x)),
+            Instruction::Value { op, .. } => value_op_effects(op),
+            Instruction::Effect { op, .. } => effect_op_effects(op),
+        }
+    }
+
+    pub fn code(&self, code: &Code) -> Effects {
+        match code {
+            Code::Label { .. } => Effects::NONE,
+            Code::Instruction(ins) => self.instruction(ins),
+        }
+    }
+}
+
+/// summarizes the effects of every function in [prog]. functions are visited callees first, a
+/// strongly connected component of the call graph shares the effects of all its members, and a
+/// function may not terminate if it has a loop, is recursive or calls something that may not
+pub fn summarize(prog: &Program) -> Summaries {
+    let graph = call_graph(prog);
+    let funcs = prog
+        .functions
+        .iter()
+        .map(|x| (x.name.clone(), x))
+        .collect::<HashMap<_, _>>();
+    let mut summaries = Summaries {
+        functions: HashMap::new(),
+    };
+    for scc in &graph.sccs {
+        let names = scc
+            .iter()
+            .map(|x| graph.graph.node_weight(*x).unwrap())
+            .collect::<Vec<_>>();
+        let mut effects = Effects {
+            may_diverge: graph.is_recursive(names[0]),
+            ..Effects::NONE
+        };
+        for name in &names {
+            let func = funcs[*name];
+            if is_cyclic_directed(&graph_from_function(func).graph) {
+                effects.may_diverge = true;
+            }
+            for code in &func.instrs {
+                //calls within the component add nothing the component doesn't already do
+                if called(code).is_some_and(|x| names.contains(&x)) {
+                    continue;
+                }
+                effects = effects.union(summaries.code(code));
+            }
+        }
+        for name in names {
+            summaries.functions.insert(name.clone(), effects);
+        }
+    }
+    summaries
+}
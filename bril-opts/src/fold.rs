@@ -0,0 +1,63 @@
+use bril_rs::{Literal, Type, ValueOps};
+
+/// normalizes a constant of type [t], bril lets integer literals stand in for floats
+pub fn const_value(t: &Type, l: &Literal) -> Literal {
+    match (t, l) {
+        (Type::Float, Literal::Int(n)) => Literal::Float(*n as f64),
+        _ => l.clone(),
+    }
+}
+
+/// bitwise equality of literals, unlike [PartialEq] this treats NaN as equal to itself
+pub fn same_literal(a: &Literal, b: &Literal) -> bool {
+    match (a, b) {
+        (Literal::Float(a), Literal::Float(b)) => a.to_bits() == b.to_bits(),
+        _ => a == b,
+    }
+}
+
+/// evaluates [op] over constant [args] with the semantics of the interpreter (wrapping integer
+/// arithmetic, IEEE floats). returns [None] if [op] can't be folded or would fail at runtime,
+/// e.g. division by zero, so the error is left for the program to hit
+pub fn eval(op: &ValueOps, args: &[Literal]) -> Option<Literal> {
+    use Literal::{Bool, Char, Float, Int};
+    let res = match (op, args) {
+        (ValueOps::Id, [a]) => a.clone(),
+
+        (ValueOps::Add, [Int(a), Int(b)]) => Int(a.wrapping_add(*b)),
+        (ValueOps::Sub, [Int(a), Int(b)]) => Int(a.wrapping_sub(*b)),
+        (ValueOps::Mul, [Int(a), Int(b)]) => Int(a.wrapping_mul(*b)),
+        (ValueOps::Div, [Int(_), Int(0)]) => return None,
+        (ValueOps::Div, [Int(a), Int(b)]) => Int(a.wrapping_div(*b)),
+        (ValueOps::Eq, [Int(a), Int(b)]) => Bool(a == b),
+        (ValueOps::Lt, [Int(a), Int(b)]) => Bool(a < b),
+        (ValueOps::Gt, [Int(a), Int(b)]) => Bool(a > b),
+        (ValueOps::Le, [Int(a), Int(b)]) => Bool(a <= b),
+        (ValueOps::Ge, [Int(a), Int(b)]) => Bool(a >= b),
+
+        (ValueOps::Not, [Bool(a)]) => Bool(!a),
+        (ValueOps::And, [Bool(a), Bool(b)]) => Bool(*a && *b),
+        (ValueOps::Or, [Bool(a), Bool(b)]) => Bool(*a || *b),
+
+        (ValueOps::Fadd, [Float(a), Float(b)]) => Float(a + b),
+        (ValueOps::Fsub, [Float(a), Float(b)]) => Float(a - b),
+        (ValueOps::Fmul, [Float(a), Float(b)]) => Float(a * b),
+        (ValueOps::Fdiv, [Float(a), Float(b)]) => Float(a / b),
+        (ValueOps::Feq, [Float(a), Float(b)]) => Bool(a == b),
+        (ValueOps::Flt, [Float(a), Float(b)]) => Bool(a < b),
+        (ValueOps::Fgt, [Float(a), Float(b)]) => Bool(a > b),
+        (ValueOps::Fle, [Float(a), Float(b)]) => Bool(a <= b),
+        (ValueOps::Fge, [Float(a), Float(b)]) => Bool(a >= b),
+
+        (ValueOps::Ceq, [Char(a), Char(b)]) => Bool(a == b),
+        (ValueOps::Clt, [Char(a), Char(b)]) => Bool(a < b),
+        (ValueOps::Cgt, [Char(a), Char(b)]) => Bool(a > b),
+        (ValueOps::Cle, [Char(a), Char(b)]) => Bool(a <= b),
+        (ValueOps::Cge, [Char(a), Char(b)]) => Bool(a >= b),
+        (ValueOps::Char2int, [Char(a)]) => Int(u32::from(*a).into()),
+        (ValueOps::Int2char, [Int(a)]) => Char(u32::try_from(*a).ok().and_then(char::from_u32)?),
+
+        _ => return None,
+    };
+    Some(res)
+}
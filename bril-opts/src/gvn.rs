@@ -0,0 +1,258 @@
+use std::collections::{HashMap, HashSet};
+
+use bril_rs::{Code, Instruction, ValueOps};
+use petgraph::{algo::dominators::Dominators, graph::NodeIndex, Direction};
+
+use crate::cfg::CFG;
+use crate::dominator::dominator_analyis;
+use crate::lvn::{is_commutative, is_value, LVNLiteral, NumberedVal, OP};
+use crate::utils::BasicBlock;
+
+/// value table scoped by the dominator tree. SSA names are defined once, so the number of a
+/// variable stays valid everywhere, but an expression may only be reused by blocks dominated by
+/// the block that computed it
+struct GVNTable {
+    //maps variable to number
+    var_num: HashMap<String, u32>,
+    //maps value to number
+    value_num: HashMap<NumberedVal, u32>,
+    //maps number to the variable holding it, only while that variable's block is in scope
+    num_leader: HashMap<u32, String>,
+    //next number
+    num: u32,
+    //values and leaders added by each block on the current dominator tree path
+    scopes: Vec<(Vec<NumberedVal>, Vec<u32>)>,
+}
+
+impl GVNTable {
+    fn new() -> Self {
+        GVNTable {
+            var_num: HashMap::new(),
+            value_num: HashMap::new(),
+            num_leader: HashMap::new(),
+            num: 0,
+            scopes: vec![(vec![], vec![])],
+        }
+    }
+
+    fn push_scope(&mut self) {
+        self.scopes.push((vec![], vec![]));
+    }
+
+    fn pop_scope(&mut self) {
+        let (values, leaders) = self.scopes.pop().unwrap();
+        for val in values {
+            self.value_num.remove(&val);
+        }
+        for num in leaders {
+            self.num_leader.remove(&num);
+        }
+    }
+
+    /// number of [var], a variable not yet defined (read across a back edge) gets a fresh number
+    /// with no leader
+    fn get_var(&mut self, var: &String) -> u32 {
+        if let Some(num) = self.var_num.get(var) {
+            return *num;
+        }
+        let num = self.num;
+        self.num += 1;
+        self.var_num.insert(var.clone(), num);
+        num
+    }
+
+    /// variable holding [num] in the current block, if it is defined by a dominating block
+    fn leader(&self, num: u32) -> Option<&String> {
+        self.num_leader.get(&num)
+    }
+
+    /// makes [var] the leader of its own number
+    fn define(&mut self, var: &String) -> u32 {
+        let num = self.get_var(var);
+        self.num_leader.insert(num, var.clone());
+        self.scopes.last_mut().unwrap().1.push(num);
+        num
+    }
+
+    /// looks up [val] computed into [dest]. returns the leader of an equal value if one is in
+    /// scope, otherwise records [dest] as the leader for [val]
+    fn lookup_or_insert(&mut self, val: NumberedVal, dest: &String) -> Option<String> {
+        if let Some(leader) = self.value_num.get(&val).and_then(|num| self.leader(*num)) {
+            let leader = leader.clone();
+            let num = self.var_num[&leader];
+            self.var_num.insert(dest.clone(), num);
+            return Some(leader);
+        }
+        let num = self.define(dest);
+        self.value_num.insert(val.clone(), num);
+        self.scopes.last_mut().unwrap().0.push(val);
+        None
+    }
+}
+
+/// global value numbering over a CFG in SSA form (see [crate::ssa::to_ssa]). walks the dominator
+/// tree so a value computed in a block is reused by every block it dominates. phis are numbered
+/// too, a phi whose sources all hold the same value or that repeats another phi of the same block
+/// is removed
+pub fn gvn(cfg: &CFG<BasicBlock>) -> CFG<BasicBlock> {
+    let mut out = cfg.clone();
+    let (dominators, _) = dominator_analyis(&out);
+
+    let mut table = GVNTable::new();
+    for arg in &out.args {
+        table.define(&arg.name);
+    }
+    //redundant variables mapped to the dominating variable replacing them
+    let mut replace = HashMap::new();
+    let start = out.start();
+    gvn_block(&mut out, start, &dominators, &mut table, &mut replace);
+
+    //uses are dominated by their definition, so the replacement is valid everywhere
+    let rename = |var: &mut String| {
+        if let Some(leader) = replace.get(var) {
+            *var = String::clone(leader);
+        }
+    };
+    for block in out.graph.node_weights_mut() {
+        for (_, phi_source) in block.phi.values_mut() {
+            phi_source.iter_mut().for_each(|(var, _)| rename(var));
+        }
+        for code in block.instructions.iter_mut() {
+            if let Code::Instruction(
+                Instruction::Value { args, .. } | Instruction::Effect { args, .. },
+            ) = code
+            {
+                args.iter_mut().for_each(rename);
+            }
+        }
+        (block.uses, block.defs) = block.uses_and_defs();
+    }
+    out.recompute_defs();
+    out
+}
+
+fn gvn_block(
+    cfg: &mut CFG<BasicBlock>,
+    node: NodeIndex,
+    dominators: &Dominators<NodeIndex>,
+    table: &mut GVNTable,
+    replace: &mut HashMap<String, String>,
+) {
+    table.push_scope();
+    let preds = cfg
+        .graph
+        .neighbors_directed(node, Direction::Incoming)
+        .map(|x| cfg.graph.node_weight(x).unwrap().label.clone().unwrap())
+        .collect::<HashSet<_>>();
+    let block = cfg.graph.node_weight_mut(node).unwrap();
+
+    let mut phis = block
+        .phi
+        .iter()
+        .map(|(canonical, (dest, phi_source))| {
+            (canonical.clone(), dest.clone(), phi_source.clone())
+        })
+        .collect::<Vec<_>>();
+    phis.sort_by(|a, b| a.1.cmp(&b.1));
+    for (canonical, dest, phi_source) in phis {
+        //a phi missing a source may be undefined on that edge, leave it alone
+        let complete = preds
+            .iter()
+            .all(|pred| phi_source.iter().any(|(_, label)| label == pred));
+        let mut sources = phi_source
+            .iter()
+            .map(|(var, label)| (label.clone(), var.clone(), table.get_var(var)))
+            .collect::<Vec<_>>();
+        sources.sort();
+        if !complete {
+            table.define(&dest);
+            continue;
+        }
+
+        let mut incoming = sources
+            .iter()
+            .filter(|(_, var, _)| *var != dest)
+            .map(|(_, _, num)| *num);
+        let same = incoming.next().filter(|num| incoming.all(|x| x == *num));
+        let leader = match same.and_then(|num| Some((num, table.leader(num)?.clone()))) {
+            Some((num, leader)) => {
+                table.var_num.insert(dest.clone(), num);
+                Some(leader)
+            }
+            None => {
+                //phis are only equal within a block, the node index keeps them apart
+                let nums = std::iter::once(node.index() as u32)
+                    .chain(sources.iter().map(|(_, _, num)| *num))
+                    .collect();
+                table.lookup_or_insert((OP::Value(ValueOps::Phi), nums), &dest)
+            }
+        };
+        if let Some(leader) = leader {
+            block.phi.remove(&canonical);
+            replace.insert(dest, leader);
+        }
+    }
+
+    let mut instructions = vec![];
+    for code in std::mem::take(&mut block.instructions) {
+        let leader = match &code {
+            Code::Instruction(Instruction::Constant {
+                dest,
+                const_type,
+                value,
+                ..
+            }) => {
+                let val = (
+                    OP::Const(LVNLiteral::from_literal(const_type, value.clone())),
+                    vec![],
+                );
+                table.lookup_or_insert(val, dest).map(|x| (dest.clone(), x))
+            }
+            Code::Instruction(Instruction::Value { dest, op, args, .. }) if *op == ValueOps::Id => {
+                let num = table.get_var(&args[0]);
+                match table.leader(num).cloned() {
+                    Some(leader) => {
+                        table.var_num.insert(dest.clone(), num);
+                        Some((dest.clone(), leader))
+                    }
+                    None => {
+                        table.define(dest);
+                        None
+                    }
+                }
+            }
+            Code::Instruction(Instruction::Value { dest, op, args, .. })
+                if is_value(op) && *op != ValueOps::Phi =>
+            {
+                let mut nums = args.iter().map(|x| table.get_var(x)).collect::<Vec<_>>();
+                if is_commutative(op) {
+                    nums.sort();
+                }
+                table
+                    .lookup_or_insert((OP::Value(*op), nums), dest)
+                    .map(|x| (dest.clone(), x))
+            }
+            Code::Instruction(Instruction::Value { dest, .. }) => {
+                table.define(dest);
+                None
+            }
+            _ => None,
+        };
+        match leader {
+            Some((dest, leader)) => {
+                replace.insert(dest, leader);
+            }
+            None => instructions.push(code),
+        }
+    }
+    block.instructions = instructions;
+
+    let children = dominators
+        .immediately_dominated_by(node)
+        .filter(|x| *x != node)
+        .collect::<Vec<_>>();
+    for child in children {
+        gvn_block(cfg, child, dominators, table, replace);
+    }
+    table.pop_scope();
+}
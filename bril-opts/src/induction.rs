@@ -0,0 +1,308 @@
+use std::collections::HashMap;
+
+use bril_rs::{Code, Instruction, Literal, ValueOps};
+use petgraph::{algo::dominators::Dominators, graph::NodeIndex, Direction};
+
+use crate::cfg::CFG;
+use crate::loops::Loop;
+use crate::utils::BasicBlock;
+
+/// integer expression over values that don't change while a loop runs
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Invariant {
+    Var(String),
+    Const(i64),
+    Add(Box<Invariant>, Box<Invariant>),
+    Sub(Box<Invariant>, Box<Invariant>),
+    Mul(Box<Invariant>, Box<Invariant>),
+}
+
+impl Invariant {
+    pub fn add(a: Invariant, b: Invariant) -> Invariant {
+        match (a, b) {
+            (Invariant::Const(a), Invariant::Const(b)) => Invariant::Const(a.wrapping_add(b)),
+            (Invariant::Const(0), x) | (x, Invariant::Const(0)) => x,
+            (a, b) => Invariant::Add(Box::new(a), Box::new(b)),
+        }
+    }
+
+    pub fn sub(a: Invariant, b: Invariant) -> Invariant {
+        match (a, b) {
+            (Invariant::Const(a), Invariant::Const(b)) => Invariant::Const(a.wrapping_sub(b)),
+            (x, Invariant::Const(0)) => x,
+            (a, b) => Invariant::Sub(Box::new(a), Box::new(b)),
+        }
+    }
+
+    pub fn mul(a: Invariant, b: Invariant) -> Invariant {
+        match (a, b) {
+            (Invariant::Const(a), Invariant::Const(b)) => Invariant::Const(a.wrapping_mul(b)),
+            (Invariant::Const(0), _) | (_, Invariant::Const(0)) => Invariant::Const(0),
+            (Invariant::Const(1), x) | (x, Invariant::Const(1)) => x,
+            (a, b) => Invariant::Mul(Box::new(a), Box::new(b)),
+        }
+    }
+}
+
+/// value of an induction variable as a linear function of a basic one,
+/// `base + iv * scale + offset`
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Linear {
+    /// the header phi of the basic induction variable
+    pub iv: String,
+    pub scale: Invariant,
+    pub offset: Invariant,
+    /// pointer the value is added to with `ptradd`, [None] for integers
+    pub base: Option<Invariant>,
+}
+
+/// a header phi `i = phi init step_var` where `step_var = i + step` on every back edge
+#[derive(Debug, Clone)]
+pub struct BasicIV {
+    /// value of the variable entering the loop from the preheader
+    pub init: Invariant,
+    pub step: Invariant,
+    /// destination of the `add` (or `sub`) performing the step
+    pub update: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct InductionVars {
+    /// basic induction variables by header phi
+    pub basic: HashMap<String, BasicIV>,
+    /// every induction variable of the loop (basic ones included) with its linear form
+    pub linear: HashMap<String, Linear>,
+}
+
+/// finds the induction variables of [l] in a CFG in SSA form. copies are looked through, so
+/// `x = id i` has the same form as `i`. [l] must have a preheader
+pub fn induction_vars(
+    cfg: &CFG<BasicBlock>,
+    l: &Loop,
+    dominators: &Dominators<NodeIndex>,
+) -> InductionVars {
+    let preheader = l.preheader.unwrap();
+    let preheader_label = cfg
+        .graph
+        .node_weight(preheader)
+        .unwrap()
+        .label
+        .clone()
+        .unwrap();
+    let latch_labels = cfg
+        .graph
+        .neighbors_directed(l.header, Direction::Incoming)
+        .filter(|x| l.contains(*x))
+        .map(|x| cfg.graph.node_weight(x).unwrap().label.clone().unwrap())
+        .collect::<Vec<_>>();
+
+    let mut defs = HashMap::new();
+    for node in cfg.graph.node_indices() {
+        for code in &cfg.graph.node_weight(node).unwrap().instructions {
+            if let Code::Instruction(
+                ins @ (Instruction::Constant { dest, .. } | Instruction::Value { dest, .. }),
+            ) = code
+            {
+                defs.insert(dest.clone(), (node, ins));
+            }
+        }
+    }
+    let analysis = Analysis {
+        cfg,
+        l,
+        dominators,
+        preheader,
+        defs,
+    };
+
+    let mut basic = HashMap::new();
+    let mut linear = HashMap::new();
+    let header = cfg.graph.node_weight(l.header).unwrap();
+    for (phi_dest, phi_source) in header.phi.values() {
+        let init = phi_source
+            .iter()
+            .find(|(_, label)| *label == preheader_label)
+            .map(|(var, _)| var.clone());
+        let back = phi_source
+            .iter()
+            .filter(|(_, label)| latch_labels.contains(label))
+            .map(|(var, _)| var.clone())
+            .collect::<Vec<_>>();
+        let (init, update) = match (init, back.first()) {
+            (Some(init), Some(update))
+                if back.len() == latch_labels.len()
+                    && back.iter().all(|x| x == update)
+                    && phi_source.len() == back.len() + 1 =>
+            {
+                (init, update.clone())
+            }
+            _ => continue,
+        };
+        if let Some((update, step)) = analysis.step(phi_dest, &update) {
+            let init = analysis.invariant(&init).unwrap_or(Invariant::Var(init));
+            basic.insert(phi_dest.clone(), BasicIV { init, step, update });
+            linear.insert(
+                phi_dest.clone(),
+                Linear {
+                    iv: phi_dest.clone(),
+                    scale: Invariant::Const(1),
+                    offset: Invariant::Const(0),
+                    base: None,
+                },
+            );
+        }
+    }
+
+    //definitions dominate their uses, so reverse postorder sees the arguments of each
+    //instruction first
+    for node in cfg.reverse_postorder() {
+        if !l.contains(node) {
+            continue;
+        }
+        for code in &cfg.graph.node_weight(node).unwrap().instructions {
+            if let Code::Instruction(Instruction::Value { dest, op, args, .. }) = code {
+                if let Some(form) = analysis.derive(&linear, op, args) {
+                    linear.insert(dest.clone(), form);
+                }
+            }
+        }
+    }
+
+    InductionVars { basic, linear }
+}
+
+struct Analysis<'a> {
+    cfg: &'a CFG<BasicBlock>,
+    l: &'a Loop,
+    dominators: &'a Dominators<NodeIndex>,
+    preheader: NodeIndex,
+    //defining block and instruction of every variable not defined by a phi
+    defs: HashMap<String, (NodeIndex, &'a Instruction)>,
+}
+
+impl<'a> Analysis<'a> {
+    /// follows copies back to the variable they were made from
+    fn resolve(&self, var: &String) -> String {
+        let mut var = var.clone();
+        while let Some((
+            _,
+            Instruction::Value {
+                op: ValueOps::Id,
+                args,
+                ..
+            },
+        )) = self.defs.get(&var)
+        {
+            var = args[0].clone();
+        }
+        var
+    }
+
+    /// [var] as an invariant of the loop, if its value is available in the preheader
+    fn invariant(&self, var: &String) -> Option<Invariant> {
+        if self.cfg.args.iter().any(|x| x.name == *var) {
+            return Some(Invariant::Var(var.clone()));
+        }
+        match self.defs.get(var) {
+            Some((
+                _,
+                Instruction::Constant {
+                    value: Literal::Int(n),
+                    ..
+                },
+            )) => Some(Invariant::Const(*n)),
+            Some((node, _)) if !self.l.contains(*node) => self
+                .dominators
+                .dominators(self.preheader)
+                .is_some_and(|mut doms| doms.any(|x| x == *node))
+                .then(|| Invariant::Var(var.clone())),
+            _ => None,
+        }
+    }
+
+    /// if [update] is [phi_dest] plus an invariant, returns the instruction computing it and
+    /// the invariant
+    fn step(&self, phi_dest: &String, update: &String) -> Option<(String, Invariant)> {
+        let update = self.resolve(update);
+        let (_, ins) = self.defs.get(&update)?;
+        let (op, args) = match ins {
+            Instruction::Value { op, args, .. } => (op, args),
+            _ => return None,
+        };
+        let is_iv = |x: &String| self.resolve(x) == *phi_dest;
+        let step = match (op, args.as_slice()) {
+            (ValueOps::Add, [a, b]) if is_iv(a) => self.invariant(b)?,
+            (ValueOps::Add, [a, b]) if is_iv(b) => self.invariant(a)?,
+            (ValueOps::Sub, [a, b]) if is_iv(a) => {
+                Invariant::sub(Invariant::Const(0), self.invariant(b)?)
+            }
+            _ => return None,
+        };
+        Some((update, step))
+    }
+
+    /// linear form of `op args` if it is an induction variable
+    fn derive(
+        &self,
+        linear: &HashMap<String, Linear>,
+        op: &ValueOps,
+        args: &Vec<String>,
+    ) -> Option<Linear> {
+        let form = |x: &String| linear.get(x).cloned();
+        let int_form = |x: &String| form(x).filter(|x| x.base.is_none());
+        let scaled = |x: Linear, a: Invariant| Linear {
+            scale: Invariant::mul(x.scale, a.clone()),
+            offset: Invariant::mul(x.offset, a),
+            ..x
+        };
+        let offset = |x: Linear, b: Invariant| Linear {
+            offset: Invariant::add(x.offset, b),
+            ..x
+        };
+
+        match (op, args.as_slice()) {
+            (ValueOps::Id, [a]) => form(a),
+            (ValueOps::Mul, [a, b]) => match (int_form(a), int_form(b)) {
+                (Some(x), None) => Some(scaled(x, self.invariant(b)?)),
+                (None, Some(x)) => Some(scaled(x, self.invariant(a)?)),
+                _ => None,
+            },
+            (ValueOps::Add, [a, b]) => match (int_form(a), int_form(b)) {
+                (Some(x), Some(y)) if x.iv == y.iv => Some(Linear {
+                    scale: Invariant::add(x.scale, y.scale),
+                    offset: Invariant::add(x.offset, y.offset),
+                    ..x
+                }),
+                (Some(x), None) => Some(offset(x, self.invariant(b)?)),
+                (None, Some(x)) => Some(offset(x, self.invariant(a)?)),
+                _ => None,
+            },
+            (ValueOps::Sub, [a, b]) => match (int_form(a), int_form(b)) {
+                (Some(x), Some(y)) if x.iv == y.iv => Some(Linear {
+                    scale: Invariant::sub(x.scale, y.scale),
+                    offset: Invariant::sub(x.offset, y.offset),
+                    ..x
+                }),
+                (Some(x), None) => Some(offset(
+                    x,
+                    Invariant::sub(Invariant::Const(0), self.invariant(b)?),
+                )),
+                (None, Some(x)) => Some(Linear {
+                    scale: Invariant::sub(Invariant::Const(0), x.scale),
+                    offset: Invariant::sub(self.invariant(a)?, x.offset),
+                    ..x
+                }),
+                _ => None,
+            },
+            (ValueOps::PtrAdd, [p, a]) => match (form(p), int_form(a)) {
+                (None, Some(x)) => Some(Linear {
+                    base: Some(self.invariant(p)?),
+                    ..x
+                }),
+                (Some(x), None) if x.base.is_some() => Some(offset(x, self.invariant(a)?)),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+}
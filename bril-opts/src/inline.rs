@@ -0,0 +1,251 @@
+use std::collections::{HashMap, HashSet};
+
+use bril_rs::{Code, EffectOps, Function, Instruction, Program, ValueOps};
+
+use crate::callgraph::{call_graph, called, CallGraph};
+use crate::utils::CFGNode;
+
+/// inlines every call to a function with at most [threshold] instructions. callees are processed
+/// before their callers, so a call is replaced by the callee with its own calls already inlined.
+/// a call within a strongly connected component of the call graph is replaced by the original
+/// body of the callee, whose calls are kept, so recursion is unrolled one level
+pub fn inline(prog: Program, threshold: usize) -> Program {
+    let graph = call_graph(&prog);
+    let original = prog
+        .functions
+        .iter()
+        .map(|x| (x.name.clone(), x.clone()))
+        .collect::<HashMap<_, _>>();
+    let order = prog
+        .functions
+        .iter()
+        .map(|x| x.name.clone())
+        .collect::<Vec<_>>();
+    let mut funcs = prog
+        .functions
+        .into_iter()
+        .map(|x| (x.name.clone(), x))
+        .collect::<HashMap<_, _>>();
+
+    for name in graph.bottom_up() {
+        let instrs = inline_calls(&funcs[&name], &funcs, &original, &graph, threshold);
+        funcs.get_mut(&name).unwrap().instrs = instrs;
+    }
+
+    Program {
+        functions: order
+            .into_iter()
+            .map(|x| funcs.remove(&x).unwrap())
+            .collect(),
+        imports: prog.imports,
+    }
+}
+
+/// number of instructions in [func], labels aren't counted
+fn size(func: &Function) -> usize {
+    func.instrs
+        .iter()
+        .filter(|x| matches!(x, Code::Instruction(_)))
+        .count()
+}
+
+fn inline_calls(
+    func: &Function,
+    funcs: &HashMap<String, Function>,
+    original: &HashMap<String, Function>,
+    graph: &CallGraph,
+    threshold: usize,
+) -> Vec<Code> {
+    let mut names = func
+        .args
+        .iter()
+        .map(|x| x.name.clone())
+        .chain(func.instrs.iter().flat_map(|x| {
+            x.uses()
+                .into_iter()
+                .chain(x.defs())
+                .chain(x.is_label())
+                .collect::<Vec<_>>()
+        }))
+        .collect::<HashSet<_>>();
+    let mut count = 0;
+
+    let mut out = vec![];
+    for code in &func.instrs {
+        let callee = called(code)
+            .and_then(|x| match graph.same_scc(&func.name, x) {
+                true => original.get(x),
+                false => funcs.get(x),
+            })
+            .filter(|x| size(x) <= threshold);
+        let callee = match callee {
+            Some(callee) => callee,
+            None => {
+                out.push(code.clone());
+                continue;
+            }
+        };
+
+        //every name of the inlined body starts with the prefix, which no name of the caller does
+        while names
+            .iter()
+            .any(|x| x.starts_with(&format!("_inl{}", count)))
+        {
+            count += 1;
+        }
+        let prefix = format!("_inl{}", count);
+        let mut body = expand(code, callee, &prefix);
+        names.insert(prefix);
+        for code in &body {
+            names.extend(code.defs().into_iter().chain(code.is_label()));
+        }
+        out.append(&mut body);
+    }
+    out
+}
+
+/// the body of [callee] replacing the call [code]. variables and labels of the callee are
+/// renamed with [prefix], arguments are copied into the parameters the callee assigns (the
+/// others are replaced by the argument) and every `ret` jumps to a label named [prefix] placed
+/// after the body, assigning the call's destination first
+fn expand(code: &Code, callee: &Function, prefix: &String) -> Vec<Code> {
+    let (actuals, dest) = match code {
+        Code::Instruction(Instruction::Value {
+            args,
+            dest,
+            op_type,
+            ..
+        }) => (args, Some((dest, op_type))),
+        Code::Instruction(Instruction::Effect { args, .. }) => (args, None),
+        _ => unreachable!(),
+    };
+    //the caller's variables don't change while the body runs, so a parameter the callee never
+    //assigns can read the argument directly instead of a copy of it
+    let assigned = callee
+        .instrs
+        .iter()
+        .flat_map(|x| x.defs())
+        .collect::<HashSet<_>>();
+    let (params, copied): (Vec<_>, Vec<_>) = callee
+        .args
+        .iter()
+        .zip(actuals)
+        .partition(|(param, _)| !assigned.contains(&param.name));
+    let params = params
+        .into_iter()
+        .map(|(param, actual)| (param.name.clone(), actual.clone()))
+        .collect::<HashMap<_, _>>();
+    let rename = |x: &String| {
+        params
+            .get(x)
+            .cloned()
+            .unwrap_or_else(|| format!("{}_{}", prefix, x))
+    };
+    //labels live apart from variables, a label named like a parameter is still prefixed
+    let rename_label = |x: &String| format!("{}_{}", prefix, x);
+    let jump = || {
+        Code::Instruction(Instruction::Effect {
+            args: vec![],
+            funcs: vec![],
+            labels: vec![prefix.clone()],
+            op: EffectOps::Jump,
+            pos: None,
+        })
+    };
+
+    let mut out = copied
+        .into_iter()
+        .map(|(param, actual)| {
+            Code::Instruction(Instruction::Value {
+                args: vec![actual.clone()],
+                dest: rename(&param.name),
+                funcs: vec![],
+                labels: vec![],
+                op: ValueOps::Id,
+                pos: None,
+                op_type: param.arg_type.clone(),
+            })
+        })
+        .collect::<Vec<_>>();
+
+    for (i, code) in callee.instrs.iter().enumerate() {
+        let code = match code.clone() {
+            Code::Label { label, pos } => Code::Label {
+                label: rename_label(&label),
+                pos,
+            },
+            Code::Instruction(Instruction::Constant {
+                dest,
+                op,
+                pos,
+                const_type,
+                value,
+            }) => Code::Instruction(Instruction::Constant {
+                dest: rename(&dest),
+                op,
+                pos,
+                const_type,
+                value,
+            }),
+            Code::Instruction(Instruction::Value {
+                args,
+                dest,
+                funcs,
+                labels,
+                op,
+                pos,
+                op_type,
+            }) => Code::Instruction(Instruction::Value {
+                args: args.iter().map(rename).collect(),
+                dest: rename(&dest),
+                funcs,
+                labels: labels.iter().map(rename_label).collect(),
+                op,
+                pos,
+                op_type,
+            }),
+            Code::Instruction(Instruction::Effect {
+                op: EffectOps::Return,
+                args,
+                pos,
+                ..
+            }) => {
+                if let (Some(value), Some((dest, op_type))) = (args.first(), dest) {
+                    out.push(Code::Instruction(Instruction::Value {
+                        args: vec![rename(value)],
+                        dest: dest.clone(),
+                        funcs: vec![],
+                        labels: vec![],
+                        op: ValueOps::Id,
+                        pos,
+                        op_type: op_type.clone(),
+                    }));
+                }
+                //the last instruction falls through to the label anyway
+                if i + 1 == callee.instrs.len() {
+                    continue;
+                }
+                jump()
+            }
+            Code::Instruction(Instruction::Effect {
+                args,
+                funcs,
+                labels,
+                op,
+                pos,
+            }) => Code::Instruction(Instruction::Effect {
+                args: args.iter().map(rename).collect(),
+                funcs,
+                labels: labels.iter().map(rename_label).collect(),
+                op,
+                pos,
+            }),
+        };
+        out.push(code);
+    }
+    out.push(Code::Label {
+        label: prefix.clone(),
+        pos: None,
+    });
+    out
+}
@@ -1,7 +1,30 @@
+pub mod adce;
+pub mod alias;
 pub mod analysis;
+pub mod callgraph;
 pub mod cfg;
+pub mod dataflow;
 pub mod dominator;
+pub mod dot;
+pub mod dse;
+pub mod effects;
+pub mod fold;
+pub mod gvn;
+pub mod induction;
+pub mod inline;
+pub mod licm;
+pub mod load;
+pub mod loops;
 pub mod lvn;
+pub mod pass;
+pub mod pre;
+pub mod profile;
+pub mod regalloc;
+pub mod report;
+pub mod sccp;
+pub mod simplify;
 pub mod ssa;
+pub mod strength;
 pub mod tdce;
 pub mod utils;
+pub mod validate;
@@ -0,0 +1,216 @@
+use std::collections::{HashMap, HashSet};
+
+use bril_rs::{Code, Function, Instruction, Literal, Program, ValueOps};
+use petgraph::{algo::dominators::Dominators, graph::NodeIndex};
+
+use crate::analysis::{defined_variable_analysis, live_variable_analysis};
+use crate::cfg::{graph_from_function, CFG};
+use crate::dominator::dominator_analyis;
+use crate::loops::{find_loops, Loop};
+use crate::utils::{BasicBlock, CFGNode, CF};
+
+/// loop-invariant code motion, moves invariant computations of every loop into its preheader
+pub fn licm(prog: Program) -> Program {
+    Program {
+        functions: prog.functions.into_iter().map(licm_function).collect(),
+        imports: prog.imports,
+    }
+}
+
+fn licm_function(func: Function) -> Function {
+    let mut cfg = graph_from_function(&func);
+    licm_cfg(&mut cfg);
+    Function {
+        instrs: cfg.flatten(),
+        ..func
+    }
+}
+
+/// runs LICM on [cfg], innermost loops first so their invariants can move further out
+pub fn licm_cfg(cfg: &mut CFG<BasicBlock>) {
+    insert_preheaders(cfg);
+    let forest = find_loops(cfg);
+    let (dominators, _) = dominator_analyis(cfg);
+    let consts = single_consts(cfg);
+    for l in forest.inner_to_outer() {
+        hoist(cfg, &forest.loops[l], &dominators, &consts);
+    }
+    cfg.recompute_defs();
+}
+
+/// gives every loop without a preheader a new block that all of its entries jump to
+pub fn insert_preheaders(cfg: &mut CFG<BasicBlock>) {
+    let mut labels = cfg
+        .graph
+        .node_weights()
+        .filter_map(|x| x.label.clone())
+        .collect::<HashSet<_>>();
+    //inserting a preheader changes the body of enclosing loops, so look again after each one
+    loop {
+        let forest = find_loops(cfg);
+        let missing = forest
+            .loops
+            .iter()
+            .find(|x| x.preheader.is_none() && !x.entries.is_empty());
+        match missing {
+            Some(l) => {
+                let (header, entries) = (l.header, l.entries.clone());
+                cfg.split_edges(&entries, header, "_LICM_P", &mut labels);
+            }
+            None => break,
+        }
+    }
+}
+
+/// variables whose only definition in the function is a `const`
+fn single_consts(cfg: &CFG<BasicBlock>) -> HashMap<String, Literal> {
+    let mut defs = HashMap::<String, Option<Literal>>::new();
+    for block in cfg.graph.node_weights() {
+        for code in &block.instructions {
+            match code {
+                Code::Instruction(Instruction::Constant { dest, value, .. }) => {
+                    defs.entry(dest.clone())
+                        .and_modify(|x| *x = None)
+                        .or_insert(Some(value.clone()));
+                }
+                Code::Instruction(Instruction::Value { dest, .. }) => {
+                    defs.insert(dest.clone(), None);
+                }
+                _ => (),
+            }
+        }
+    }
+    for arg in &cfg.args {
+        defs.insert(arg.name.clone(), None);
+    }
+    defs.into_iter()
+        .filter_map(|(var, val)| Some((var, val?)))
+        .collect()
+}
+
+/// returns true if moving [code] so that it may run when it otherwise would not is safe: it
+/// has no side effects and can't fail
+fn can_speculate(code: &Code, consts: &HashMap<String, Literal>) -> bool {
+    match code {
+        Code::Instruction(Instruction::Constant { .. }) => true,
+        Code::Instruction(Instruction::Value { op, args, .. }) => match op {
+            ValueOps::Call | ValueOps::Alloc | ValueOps::Load | ValueOps::Phi => false,
+            //not every integer is a valid character
+            ValueOps::Int2char => false,
+            ValueOps::Div => !matches!(consts.get(&args[1]), None | Some(Literal::Int(0))),
+            _ => true,
+        },
+        _ => false,
+    }
+}
+
+/// moves the invariant instructions of [l] to the end of its preheader. an instruction is
+/// invariant if every argument is defined outside the loop or by another invariant instruction.
+/// it is only moved if it is the loop's only definition of its destination, the destination is
+/// not read before it in the loop, and either its block dominates every exit of the loop or the
+/// destination is dead once the loop exits
+fn hoist(
+    cfg: &mut CFG<BasicBlock>,
+    l: &Loop,
+    dominators: &Dominators<NodeIndex>,
+    consts: &HashMap<String, Literal>,
+) {
+    let preheader = match l.preheader {
+        Some(preheader) => preheader,
+        None => return,
+    };
+    //a loop without exits can only leave through a return, which could skip the instruction
+    if l.exiting_edges.is_empty() {
+        return;
+    }
+    let (live, _) = live_variable_analysis(cfg);
+    let live_in = &live[l.header.index()];
+    let live_at_exits = l
+        .exits
+        .iter()
+        .map(|x| live[x.index()].iter().cloned())
+        .flatten()
+        .collect::<HashSet<_>>();
+    //the moved instruction may run when it didn't before, so its arguments must be assigned
+    let (_, defined) = defined_variable_analysis(cfg);
+    let defined = &defined[preheader.index()];
+
+    let mut body = l.body.iter().copied().collect::<Vec<_>>();
+    body.sort();
+
+    let mut def_count = HashMap::<String, usize>::new();
+    for node in &body {
+        for code in &cfg.graph.node_weight(*node).unwrap().instructions {
+            for var in code.defs() {
+                *def_count.entry(var).or_default() += 1;
+            }
+        }
+    }
+
+    let dominates_exits = |node: NodeIndex| {
+        l.exiting_edges.iter().all(|(from, _)| {
+            dominators
+                .dominators(*from)
+                .is_some_and(|mut doms| doms.any(|x| x == node))
+        })
+    };
+
+    //(block, index) of each invariant instruction, in an order that respects dependencies
+    let mut invariant = vec![];
+    let mut invariant_defs = HashSet::new();
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for node in &body {
+            let block = cfg.graph.node_weight(*node).unwrap();
+            for (i, code) in block.instructions.iter().enumerate() {
+                let dest = match code.defs().into_iter().next() {
+                    Some(dest) => dest,
+                    None => continue,
+                };
+                if invariant_defs.contains(&dest)
+                    || def_count.get(&dest) != Some(&1)
+                    || live_in.contains(&dest)
+                    || !can_speculate(code, consts)
+                    || !(dominates_exits(*node) || !live_at_exits.contains(&dest))
+                {
+                    continue;
+                }
+                let args_invariant = code.uses().iter().all(|x| {
+                    invariant_defs.contains(x) || !def_count.contains_key(x) && defined.contains(x)
+                });
+                if args_invariant {
+                    invariant.push((*node, i));
+                    invariant_defs.insert(dest);
+                    changed = true;
+                }
+            }
+        }
+    }
+    if invariant.is_empty() {
+        return;
+    }
+
+    let hoisted = invariant
+        .iter()
+        .map(|(node, i)| cfg.graph.node_weight(*node).unwrap().instructions[*i].clone())
+        .collect::<Vec<_>>();
+    let moved = invariant.into_iter().collect::<HashSet<_>>();
+    for node in &body {
+        let block = cfg.graph.node_weight_mut(*node).unwrap();
+        let mut i = 0;
+        block.instructions.retain(|_| {
+            i += 1;
+            !moved.contains(&(*node, i - 1))
+        });
+        (block.uses, block.defs) = block.uses_and_defs();
+    }
+
+    let block = cfg.graph.node_weight_mut(preheader).unwrap();
+    let at = match block.control_flow() {
+        CF::Jump(_) | CF::Branch(_, _) | CF::Return => block.instructions.len() - 1,
+        _ => block.instructions.len(),
+    };
+    block.instructions.splice(at..at, hoisted);
+    (block.uses, block.defs) = block.uses_and_defs();
+}
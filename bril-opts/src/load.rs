@@ -0,0 +1,128 @@
+use std::fmt::Display;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+
+use bril_rs::{AbstractProgram, ColRow, Position, Program};
+
+/// a program that couldn't be read, at [pos] in the source if it is known
+#[derive(Debug, Clone)]
+pub struct LoadError {
+    pub message: String,
+    pub pos: Option<Position>,
+}
+
+impl Display for LoadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.pos {
+            Some(Position { pos, src, .. }) => write!(
+                f,
+                "{}:{}:{}: {}",
+                src.as_deref().unwrap_or("<stdin>"),
+                pos.row,
+                pos.col,
+                self.message
+            ),
+            None => write!(f, "{}", self.message),
+        }
+    }
+}
+
+impl LoadError {
+    fn at(message: String, pos: Option<ColRow>, src: &Option<String>) -> Self {
+        LoadError {
+            message,
+            pos: pos.map(|pos| Position {
+                pos,
+                pos_end: None,
+                src: src.clone(),
+            }),
+        }
+    }
+
+    /// the line of [source] the error is on with a caret under its column, empty without a
+    /// position
+    pub fn excerpt(&self, source: &str) -> String {
+        let pos = match &self.pos {
+            Some(x) => &x.pos,
+            None => return String::new(),
+        };
+        match source.lines().nth(pos.row.saturating_sub(1) as usize) {
+            Some(line) => format!("{}\n{:>width$}", line, "^", width = pos.col.max(1) as usize),
+            None => String::new(),
+        }
+    }
+}
+
+/// 1-based row and column of the byte [offset] in [source]
+fn col_row(source: &str, offset: usize) -> ColRow {
+    let before = &source[..offset.min(source.len())];
+    let row = before.matches('\n').count() + 1;
+    let col = before.len() - before.rfind('\n').map_or(0, |x| x + 1) + 1;
+    ColRow {
+        row: row as u64,
+        col: col as u64,
+    }
+}
+
+/// byte offset of a parse error in the panic message of bril2json, which debug-prints the
+/// parser's error: `location: N` for a bad or missing token, `token: (N, ...)` for an
+/// unexpected one
+fn parse_error_offset(message: &str) -> Option<usize> {
+    ["location: ", "token: ("].iter().find_map(|key| {
+        let rest = &message[message.find(key)? + key.len()..];
+        let digits = rest
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        rest[..digits].parse().ok()
+    })
+}
+
+/// parses [source] in the text format with bril2json, keeping the position of every
+/// instruction. bril2json panics on a syntax error, which is caught and turned into an error
+/// at the place it names
+fn parse_text(source: &str, src: &Option<String>) -> Result<AbstractProgram, LoadError> {
+    let hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(|_| {}));
+    let result = catch_unwind(AssertUnwindSafe(|| {
+        bril2json::parse_abstract_program_from_read(source.as_bytes(), true, true, src.clone())
+    }));
+    std::panic::set_hook(hook);
+    result.map_err(|e| {
+        let message = e
+            .downcast_ref::<String>()
+            .cloned()
+            .or_else(|| e.downcast_ref::<&str>().map(|x| x.to_string()))
+            .unwrap_or_default();
+        let pos = parse_error_offset(&message).map(|x| col_row(source, x));
+        LoadError::at(format!("syntax error: {}", message), pos, src)
+    })
+}
+
+/// reads [source], a program in JSON, or in the text format if [text] is set. [src] is the name
+/// of the file it comes from, used in positions and errors
+pub fn load(source: &str, text: bool, src: Option<String>) -> Result<Program, LoadError> {
+    let prog = if text {
+        parse_text(source, &src)?
+    } else {
+        serde_json::from_str::<AbstractProgram>(source).map_err(|e| {
+            let pos = ColRow {
+                row: e.line() as u64,
+                col: e.column() as u64,
+            };
+            //serde_json ends its message with the position, which is printed in front instead
+            let message = e.to_string();
+            let message = message.rsplit_once(" at line ").map_or(&*message, |x| x.0);
+            LoadError::at(message.to_string(), Some(pos), &src)
+        })?
+    };
+    prog.try_into()
+        .map_err(|e: bril_rs::conversion::PositionalConversionError| {
+            let pos = e.pos.map(|pos| Position {
+                src: pos.src.or(src),
+                ..pos
+            });
+            LoadError {
+                message: e.e.to_string(),
+                pos,
+            }
+        })
+}
@@ -0,0 +1,182 @@
+use std::collections::{HashMap, HashSet};
+
+use petgraph::visit::EdgeRef;
+use petgraph::{graph::NodeIndex, Direction};
+
+use crate::cfg::CFG;
+use crate::dominator::dominator_analyis;
+use crate::utils::CFGNode;
+
+/// a natural loop, all back edges into the same header are merged into one loop
+#[derive(Debug, Clone)]
+pub struct Loop {
+    pub header: NodeIndex,
+    /// sources of the back edges into [header]
+    pub latches: Vec<NodeIndex>,
+    /// blocks of the loop, including the header
+    pub body: HashSet<NodeIndex>,
+    /// predecessors of the header outside the loop
+    pub entries: Vec<NodeIndex>,
+    /// the only entry, if the header is its only successor. code placed at its end runs once
+    /// before the loop
+    pub preheader: Option<NodeIndex>,
+    /// edges leaving the loop, from a block in the body to one outside
+    pub exiting_edges: Vec<(NodeIndex, NodeIndex)>,
+    /// targets of [exiting_edges]
+    pub exits: Vec<NodeIndex>,
+    /// index of the closest enclosing loop
+    pub parent: Option<usize>,
+    /// indices of the loops directly nested in this one
+    pub children: Vec<usize>,
+    /// nesting depth, 1 for an outermost loop
+    pub depth: usize,
+}
+
+impl Loop {
+    pub fn contains(&self, node: NodeIndex) -> bool {
+        self.body.contains(&node)
+    }
+}
+
+/// loop nesting forest of a CFG
+#[derive(Debug, Clone)]
+pub struct LoopForest {
+    /// loops ordered by header in reverse postorder, so outer loops come before inner ones
+    pub loops: Vec<Loop>,
+    //innermost loop of each block
+    innermost: HashMap<NodeIndex, usize>,
+}
+
+impl LoopForest {
+    /// innermost loop containing [node]
+    pub fn innermost(&self, node: NodeIndex) -> Option<usize> {
+        self.innermost.get(&node).copied()
+    }
+
+    /// number of loops containing [node], 0 outside of any loop
+    pub fn depth(&self, node: NodeIndex) -> usize {
+        self.innermost(node).map_or(0, |x| self.loops[x].depth)
+    }
+
+    /// loops not nested in any other loop
+    pub fn roots(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..self.loops.len()).filter(|x| self.loops[*x].parent.is_none())
+    }
+
+    /// returns true if [from] -> [to] is a back edge
+    pub fn is_back_edge(&self, from: NodeIndex, to: NodeIndex) -> bool {
+        self.loops
+            .iter()
+            .any(|x| x.header == to && x.latches.contains(&from))
+    }
+
+    /// loops ordered innermost first, the order in which loop optimizations should visit them
+    pub fn inner_to_outer(&self) -> Vec<usize> {
+        let mut order = (0..self.loops.len()).collect::<Vec<_>>();
+        order.sort_by_key(|x| std::cmp::Reverse(self.loops[*x].depth));
+        order
+    }
+}
+
+/// finds the natural loops of [cfg]. an edge is a back edge if its target dominates its source,
+/// the loop body is every block that reaches the source without passing through the header
+pub fn find_loops<T: Clone + std::fmt::Debug + std::fmt::Display + CFGNode>(
+    cfg: &CFG<T>,
+) -> LoopForest {
+    let (dominators, _) = dominator_analyis(cfg);
+    let order = cfg.reverse_postorder();
+
+    let mut loops = vec![];
+    for header in &order {
+        let latches = cfg
+            .graph
+            .neighbors_directed(*header, Direction::Incoming)
+            .filter(|pred| {
+                dominators
+                    .dominators(*pred)
+                    .is_some_and(|mut doms| doms.any(|x| x == *header))
+            })
+            .collect::<HashSet<_>>();
+        if latches.is_empty() {
+            continue;
+        }
+        let mut latches = latches.into_iter().collect::<Vec<_>>();
+        latches.sort();
+
+        let mut body = HashSet::from([*header]);
+        let mut work_list = latches.clone();
+        while let Some(node) = work_list.pop() {
+            //unreachable blocks may jump into the loop but are not part of it
+            if dominators.dominators(node).is_some() && body.insert(node) {
+                work_list.extend(cfg.graph.neighbors_directed(node, Direction::Incoming));
+            }
+        }
+
+        let mut entries = cfg
+            .graph
+            .neighbors_directed(*header, Direction::Incoming)
+            .filter(|x| !body.contains(x))
+            .collect::<HashSet<_>>()
+            .into_iter()
+            .collect::<Vec<_>>();
+        entries.sort();
+        let preheader = match entries.as_slice() {
+            [entry] if cfg.graph.neighbors(*entry).all(|x| x == *header) => Some(*entry),
+            _ => None,
+        };
+
+        let mut exiting_edges = body
+            .iter()
+            .flat_map(|from| cfg.graph.edges(*from).map(|e| (*from, e.target())))
+            .filter(|(_, to)| !body.contains(to))
+            .collect::<HashSet<_>>()
+            .into_iter()
+            .collect::<Vec<_>>();
+        exiting_edges.sort();
+        let mut exits = exiting_edges.iter().map(|(_, to)| *to).collect::<Vec<_>>();
+        exits.sort();
+        exits.dedup();
+
+        loops.push(Loop {
+            header: *header,
+            latches,
+            body,
+            entries,
+            preheader,
+            exiting_edges,
+            exits,
+            parent: None,
+            children: vec![],
+            depth: 1,
+        });
+    }
+
+    //natural loops with different headers are either disjoint or nested, the parent of a loop
+    //is the smallest other loop containing its header
+    for i in 0..loops.len() {
+        loops[i].parent = (0..loops.len())
+            .filter(|j| *j != i && loops[*j].contains(loops[i].header))
+            .min_by_key(|j| loops[*j].body.len());
+    }
+    //headers are in reverse postorder, so a parent is always visited before its children
+    for i in 0..loops.len() {
+        if let Some(parent) = loops[i].parent {
+            loops[i].depth = loops[parent].depth + 1;
+            loops[parent].children.push(i);
+        }
+    }
+
+    let mut innermost = HashMap::new();
+    for (i, l) in loops.iter().enumerate() {
+        for node in &l.body {
+            let deeper = innermost
+                .get(node)
+                .map_or(true, |x: &usize| loops[*x].depth < l.depth);
+            if deeper {
+                innermost.insert(*node, i);
+            }
+        }
+    }
+
+    LoopForest { loops, innermost }
+}
@@ -3,44 +3,230 @@ use std::{
     hash::Hash,
 };
 
-use bril_rs::{Code, ConstOps, Function, Instruction, Literal, Program, Type, ValueOps};
+use bril_rs::{Code, ConstOps, EffectOps, Function, Instruction, Literal, Program, Type, ValueOps};
+use petgraph::graph::NodeIndex;
+use petgraph::Direction;
 
-use crate::utils::{code_to_bb, CFGNode};
+use crate::alias::{points_to, PointsTo, Targets};
+use crate::cfg::{graph_from_function, CFG};
+use crate::effects::{summarize, value_op_effects, Summaries};
+use crate::fold::{const_value, eval, same_literal};
+use crate::utils::{BasicBlock, CFGNode};
 
 pub fn lvn(prog: Program) -> Program {
+    lvn_program(prog, false)
+}
+
+/// superlocal value numbering, carries the table of a block into the successors that can only
+/// be reached through it
+pub fn ebb_lvn(prog: Program) -> Program {
+    lvn_program(prog, true)
+}
+
+fn lvn_program(prog: Program, extended: bool) -> Program {
+    let summaries = summarize(&prog);
     Program {
-        functions: prog.functions.into_iter().map(|x| lvn_pass(x)).collect(),
+        functions: prog
+            .functions
+            .into_iter()
+            .map(|x| lvn_pass(x, &summaries, extended))
+            .collect(),
         ..prog
     }
 }
 
-fn lvn_pass(func: Function) -> Function {
+fn lvn_pass(func: Function, summaries: &Summaries, extended: bool) -> Function {
+    let cfg = graph_from_function(&func);
+    let memory = points_to(&cfg);
+    let mut numbering = Numbering {
+        cfg: &cfg,
+        memory: &memory,
+        summaries,
+        temp: 0,
+        blocks: vec![vec![]; cfg.graph.node_count()],
+        visited: HashSet::new(),
+    };
+    if extended {
+        //every block that isn't the only successor of another starts an extended block, then
+        //whatever is left, e.g. unreachable cycles
+        let roots = cfg.graph.node_indices().filter(|x| {
+            *x == cfg.start()
+                || cfg
+                    .graph
+                    .neighbors_directed(*x, Direction::Incoming)
+                    .count()
+                    != 1
+        });
+        for root in roots.chain(cfg.graph.node_indices()) {
+            if !numbering.visited.contains(&root) {
+                numbering.ebb_pass(root, LVNTable::new());
+            }
+        }
+    } else {
+        for node in cfg.graph.node_indices() {
+            numbering.bb_pass(node, &mut LVNTable::new());
+        }
+    }
+    let blocks = numbering.blocks;
+    //the blocks stay in order, so only the labels of the input are needed, with their positions
+    let labels = func
+        .instrs
+        .iter()
+        .filter_map(|code| match code {
+            Code::Label { label, .. } => Some((label.clone(), code.clone())),
+            _ => None,
+        })
+        .collect::<HashMap<_, _>>();
     Function {
-        instrs: code_to_bb(func.instrs)
-            .into_iter()
-            .map(|x| lvn_bb_pass(x.instructions))
-            .flatten()
+        instrs: cfg
+            .graph
+            .node_indices()
+            .zip(blocks)
+            .flat_map(|(node, code)| {
+                let block = cfg.graph.node_weight(node).unwrap();
+                let label = block.label.as_ref().and_then(|x| labels.get(x)).cloned();
+                label.into_iter().chain(code)
+            })
             .collect(),
         ..func
     }
 }
 
-fn lvn_bb_pass(code: Vec<Code>) -> Vec<Code> {
+/// state of numbering the blocks of a function
+struct Numbering<'a> {
+    cfg: &'a CFG<BasicBlock>,
+    memory: &'a PointsTo,
+    summaries: &'a Summaries,
+    //temporaries are numbered across the function, a block may use those of its predecessor
+    temp: usize,
+    //numbered code of each block
+    blocks: Vec<Vec<Code>>,
+    visited: HashSet<NodeIndex>,
+}
+
+impl Numbering<'_> {
+    fn bb_pass(&mut self, node: NodeIndex, table: &mut LVNTable) {
+        let block = self.cfg.graph.node_weight(node).unwrap();
+        self.visited.insert(node);
+        self.blocks[node.index()] = lvn_bb_pass(
+            node,
+            block.instructions.clone(),
+            table,
+            &mut self.temp,
+            self.memory,
+            self.summaries,
+        );
+    }
+
+    ///numbers [node] starting from [table], then each successor whose only predecessor is
+    ///[node] with its own copy of the table, which is dropped once that subtree is done
+    fn ebb_pass(&mut self, node: NodeIndex, mut table: LVNTable) {
+        self.bb_pass(node, &mut table);
+        let cfg = self.cfg;
+        for succ in cfg.graph.neighbors(node) {
+            let preds = cfg.graph.neighbors_directed(succ, Direction::Incoming);
+            if preds.count() == 1 && succ != cfg.start() && !self.visited.contains(&succ) {
+                self.ebb_pass(succ, table.clone());
+            }
+        }
+    }
+}
+
+/// pointer read or written by [ins], if it accesses memory
+fn accessed(ins: &Instruction) -> Option<&String> {
+    match ins {
+        Instruction::Value {
+            op: ValueOps::Load,
+            args,
+            ..
+        }
+        | Instruction::Effect {
+            op: EffectOps::Store | EffectOps::Free,
+            args,
+            ..
+        } => args.first(),
+        _ => None,
+    }
+}
+
+fn lvn_bb_pass(
+    node: NodeIndex,
+    code: Vec<Code>,
+    lvntable: &mut LVNTable,
+    temp: &mut usize,
+    memory: &PointsTo,
+    summaries: &Summaries,
+) -> Vec<Code> {
     let mut new_block = vec![];
     let last_def = last_def(&code);
-    let mut temp = 0;
-    let mut lvntable = LVNTable::new();
 
     for (line, c) in code.into_iter().enumerate() {
         match c {
             Code::Label { .. } => new_block.push(c),
             Code::Instruction(ins) => {
-                let (dest, val) = lvntable.extract_dest_val(&ins);
+                //targets of the accessed pointer, under its name before the instruction
+                let targets = accessed(&ins).map(|x| memory.targets(node, line, x));
+                let ins = match &targets {
+                    Some(targets) => lvntable.forward_load(ins, targets, memory),
+                    None => ins,
+                };
+                let effects = summaries.instruction(&ins);
+                let (dest, mut val) = lvntable.extract_dest_val(&ins);
+                //a call to a pure function is numbered like any other value
+                if let Instruction::Value {
+                    op: ValueOps::Call,
+                    funcs,
+                    ..
+                } = &ins
+                {
+                    if effects.is_pure() {
+                        val.0 = OP::Call(funcs[0].clone());
+                    }
+                }
+                //constants are folded and identities become copies
+                if let Instruction::Value { .. } = &ins {
+                    val = lvntable.simplify(val);
+                }
+                //a call may write any memory that escapes the function
+                let clobbers = effects.writes
+                    && matches!(
+                        ins,
+                        Instruction::Value {
+                            op: ValueOps::Call,
+                            ..
+                        } | Instruction::Effect {
+                            op: EffectOps::Call,
+                            ..
+                        }
+                    );
+                let contents = match &ins {
+                    Instruction::Effect {
+                        op: EffectOps::Store,
+                        args,
+                        ..
+                    } => Some(lvntable.get_var(&args[1])),
+                    _ => None,
+                };
+                let ins = match (ins, &val.0) {
+                    (
+                        Instruction::Value {
+                            dest, pos, op_type, ..
+                        },
+                        OP::Const(value),
+                    ) => Instruction::Constant {
+                        dest,
+                        op: ConstOps::Const,
+                        pos,
+                        const_type: op_type,
+                        value: value.to_literal(),
+                    },
+                    (ins, _) => ins,
+                };
                 if let Some(dest) = dest {
                     let new_dest = if last_def[line] {
                         dest.clone()
                     } else {
-                        temp += 1;
+                        *temp += 1;
                         format!("_lvn{}_{}", temp, dest)
                     };
                     //handle special case where calls, alloc, ptradd, etc. should not be tabulated
@@ -55,19 +241,39 @@ fn lvn_bb_pass(code: Vec<Code>) -> Vec<Code> {
                     //     continue;
                     // }
 
-                    new_block.push(Code::Instruction(lvntable.produce_new_ins(
-                        ins,
-                        new_dest.clone(),
-                        val,
-                    )));
+                    let ins = lvntable.produce_new_ins(ins, new_dest.clone(), val.clone());
                     let num = lvntable.get_var(&new_dest);
                     lvntable.bind_var_to_num(&dest, num);
+                    if let (OP::Value(ValueOps::Load), Some(targets)) = (&val.0, targets) {
+                        lvntable.remember(val.1[0], targets, num, memory);
+                    }
+                    if clobbers {
+                        lvntable.forget_escaped(memory);
+                    }
+                    new_block.push(Code::Instruction(ins));
                 } else {
-                    new_block.push(Code::Instruction(lvntable.produce_new_ins(
-                        ins,
-                        "".into(),
-                        val,
-                    )))
+                    let ins = lvntable.produce_new_ins(ins, "".into(), val.clone());
+                    match (&ins, targets, contents) {
+                        (
+                            Instruction::Effect {
+                                op: EffectOps::Store,
+                                ..
+                            },
+                            Some(targets),
+                            Some(num),
+                        ) => lvntable.remember(val.1[0], targets, num, memory),
+                        (
+                            Instruction::Effect {
+                                op: EffectOps::Free,
+                                ..
+                            },
+                            Some(targets),
+                            _,
+                        ) => lvntable.forget(&targets, memory),
+                        _ if clobbers => lvntable.forget_escaped(memory),
+                        _ => (),
+                    }
+                    new_block.push(Code::Instruction(ins))
                 }
             }
         }
@@ -76,27 +282,26 @@ fn lvn_bb_pass(code: Vec<Code>) -> Vec<Code> {
 }
 
 #[derive(PartialEq, Eq, Hash, Clone, Debug)]
-enum LVNLiteral {
+pub(crate) enum LVNLiteral {
     /// Integers
     Int(i64),
     /// Booleans
     Bool(bool),
-    /// Floating Points
-    Float(String),
+    /// Floating Points, by their bits so every value survives and NaN equals itself
+    Float(u64),
     /// UTF-16 Characters
     Char(char),
 }
 
 impl LVNLiteral {
-    fn from_literal(t: &Type, l: Literal) -> Self {
+    pub(crate) fn from_literal(t: &Type, l: Literal) -> Self {
+        Self::from_value(const_value(t, &l))
+    }
+    fn from_value(l: Literal) -> Self {
         match l {
-            Literal::Int(n) => match t {
-                Type::Int => LVNLiteral::Int(n),
-                Type::Float => LVNLiteral::Float(n.to_string()),
-                _ => unreachable!("Expected int or float"),
-            },
+            Literal::Int(n) => LVNLiteral::Int(n),
             Literal::Bool(b) => LVNLiteral::Bool(b),
-            Literal::Float(f) => LVNLiteral::Float(f.to_string()),
+            Literal::Float(f) => LVNLiteral::Float(f.to_bits()),
             Literal::Char(ch) => LVNLiteral::Char(ch),
         }
     }
@@ -104,21 +309,24 @@ impl LVNLiteral {
         match self {
             LVNLiteral::Int(n) => Literal::Int(*n),
             LVNLiteral::Bool(b) => Literal::Bool(*b),
-            LVNLiteral::Float(f) => Literal::Float(f.parse().unwrap()),
+            LVNLiteral::Float(f) => Literal::Float(f64::from_bits(*f)),
             LVNLiteral::Char(ch) => Literal::Char(*ch),
         }
     }
 }
 
 #[derive(PartialEq, Eq, Hash, Clone, Debug)]
-enum OP {
+pub(crate) enum OP {
     Const(LVNLiteral),
     Value(ValueOps),
+    /// call to a pure function
+    Call(String),
     Effect,
 }
 
-type NumberedVal = (OP, Vec<u32>);
+pub(crate) type NumberedVal = (OP, Vec<u32>);
 
+#[derive(Clone)]
 struct LVNTable {
     //"cloud"
     var_num: HashMap<String, u32>,
@@ -128,6 +336,11 @@ struct LVNTable {
     num_canonical: HashMap<u32, Vec<String>>,
     //next number
     num: u32,
+    //known contents of memory, as the number of a pointer, the locations it may point to and the
+    //number of the value last stored to or loaded from it
+    memory: Vec<(u32, Targets, u32)>,
+    //maps number to the value it was computed from
+    num_value: HashMap<u32, NumberedVal>,
 }
 
 impl LVNTable {
@@ -137,8 +350,67 @@ impl LVNTable {
             value_num: HashMap::new(),
             num_canonical: HashMap::new(),
             num: 0,
+            memory: vec![],
+            num_value: HashMap::new(),
+        }
+    }
+
+    ///if [ins] loads from a pointer whose contents are known, replaces it with a copy of the
+    ///value last stored to or loaded from that memory
+    fn forward_load(
+        &mut self,
+        ins: Instruction,
+        targets: &Targets,
+        memory: &PointsTo,
+    ) -> Instruction {
+        let (dest, ptr, op_type, pos) = match ins {
+            Instruction::Value {
+                op: ValueOps::Load,
+                ref dest,
+                ref args,
+                ref op_type,
+                ref pos,
+                ..
+            } => (dest, &args[0], op_type, pos.clone()),
+            _ => return ins,
+        };
+        let num = self.get_var(ptr);
+        let known = self.memory.iter().find_map(|(x, stored, value)| {
+            (*x == num || memory.must_alias(stored, targets))
+                .then(|| self.num_canonical.get(value).and_then(|x| x.first()))
+                .flatten()
+        });
+        match known {
+            Some(value) => Instruction::Value {
+                args: vec![value.clone()],
+                dest: dest.clone(),
+                funcs: vec![],
+                labels: vec![],
+                op: ValueOps::Id,
+                pos,
+                op_type: op_type.clone(),
+            },
+            None => ins,
         }
     }
+
+    ///records that the memory [targets] at pointer [num] holds [value], forgetting every
+    ///other memory it may overlap
+    fn remember(&mut self, num: u32, targets: Targets, value: u32, memory: &PointsTo) {
+        self.forget(&targets, memory);
+        self.memory.push((num, targets, value));
+    }
+
+    ///forgets the contents of memory that may overlap [targets]
+    fn forget(&mut self, targets: &Targets, memory: &PointsTo) {
+        self.memory
+            .retain(|(_, x, _)| !memory.may_alias(x, targets));
+    }
+
+    ///forgets the contents of memory that a call may write
+    fn forget_escaped(&mut self, memory: &PointsTo) {
+        self.memory.retain(|(_, x, _)| !memory.escapes(x));
+    }
     ///safely retrieves num associated with var [name] by creating a new entry if [name] has not been tabulated
     fn get_var(&mut self, name: &String) -> u32 {
         match self.var_num.get(name) {
@@ -156,6 +428,7 @@ impl LVNTable {
     fn new_value(&mut self, exp: &NumberedVal, canonical_name: &String) {
         self.num += 1;
         self.value_num.insert(exp.clone(), self.num);
+        self.num_value.insert(self.num, exp.clone());
         self.var_num.insert(canonical_name.clone(), self.num);
         if self.num_canonical.contains_key(&self.num) {
             self.num_canonical
@@ -196,6 +469,117 @@ impl LVNTable {
         }
     }
 
+    ///the constant numbered [num], if it is one
+    fn constant(&self, num: u32) -> Option<Literal> {
+        match self.num_value.get(&num) {
+            Some((OP::Const(l), _)) => Some(l.to_literal()),
+            _ => None,
+        }
+    }
+
+    ///simplifies [exp] using what is known about its arguments. constants are folded with the
+    ///semantics of the interpreter, identities such as `x * 1` become copies, and the arguments
+    ///of commutative ops are sorted so that `add a b` and `add b a` get the same number
+    fn simplify(&self, exp: NumberedVal) -> NumberedVal {
+        let op = match &exp.0 {
+            OP::Value(op) if *op != ValueOps::Id && is_value(op) => op.clone(),
+            _ => return exp,
+        };
+        let mut args = exp.1;
+        if is_commutative(&op) {
+            args.sort();
+        }
+        let consts = args.iter().map(|x| self.constant(*x)).collect::<Vec<_>>();
+        let folded = consts
+            .iter()
+            .cloned()
+            .collect::<Option<Vec<_>>>()
+            .and_then(|x| eval(&op, &x));
+        if let Some(value) = folded {
+            return (OP::Const(LVNLiteral::from_value(value)), vec![]);
+        }
+        self.identity(&op, &args, &consts)
+            .unwrap_or((OP::Value(op), args))
+    }
+
+    ///applies the algebraic identities of [op] to [args], of which [consts] are known
+    fn identity(
+        &self,
+        op: &ValueOps,
+        args: &[u32],
+        consts: &[Option<Literal>],
+    ) -> Option<NumberedVal> {
+        use Literal::{Bool, Float, Int};
+        let copy = |x: u32| Some((OP::Value(ValueOps::Id), vec![x]));
+        let constant = |l: Literal| Some((OP::Const(LVNLiteral::from_value(l)), vec![]));
+        let (x, y) = match args {
+            [x, y] => (*x, *y),
+            //`not not b` is `b`, if some variable still holds it
+            [x] => {
+                return match (op, self.num_value.get(x)) {
+                    (ValueOps::Not, Some((OP::Value(ValueOps::Not), inner)))
+                        if self
+                            .num_canonical
+                            .get(&inner[0])
+                            .is_some_and(|x| !x.is_empty()) =>
+                    {
+                        copy(inner[0])
+                    }
+                    _ => None,
+                }
+            }
+            _ => return None,
+        };
+        if x == y {
+            //floats are left alone, NaN isn't equal to itself
+            return match op {
+                ValueOps::Sub => constant(Int(0)),
+                ValueOps::And | ValueOps::Or => copy(x),
+                ValueOps::Eq | ValueOps::Le | ValueOps::Ge => constant(Bool(true)),
+                ValueOps::Ceq | ValueOps::Cle | ValueOps::Cge => constant(Bool(true)),
+                ValueOps::Lt | ValueOps::Gt | ValueOps::Clt | ValueOps::Cgt => {
+                    constant(Bool(false))
+                }
+                _ => None,
+            };
+        }
+        //the argument next to the constant [c], which must be on the right unless [op] commutes
+        let beside = |c: Literal| {
+            let is = |x: &Option<Literal>| x.as_ref().is_some_and(|x| same_literal(x, &c));
+            if is(&consts[1]) {
+                Some(x)
+            } else if is_commutative(op) && is(&consts[0]) {
+                Some(y)
+            } else {
+                None
+            }
+        };
+        match op {
+            ValueOps::Add | ValueOps::Sub => beside(Int(0)).and_then(copy),
+            ValueOps::Mul => match (beside(Int(1)), beside(Int(0))) {
+                (Some(x), _) => copy(x),
+                (_, Some(_)) => constant(Int(0)),
+                _ => None,
+            },
+            ValueOps::Div => beside(Int(1)).and_then(copy),
+            ValueOps::And => match (beside(Bool(true)), beside(Bool(false))) {
+                (Some(x), _) => copy(x),
+                (_, Some(_)) => constant(Bool(false)),
+                _ => None,
+            },
+            ValueOps::Or => match (beside(Bool(false)), beside(Bool(true))) {
+                (Some(x), _) => copy(x),
+                (_, Some(_)) => constant(Bool(true)),
+                _ => None,
+            },
+            //only identities that are exact for every float, `x + 0.0` turns -0.0 into 0.0
+            ValueOps::Fmul | ValueOps::Fdiv => beside(Float(1.0)).and_then(copy),
+            ValueOps::Fadd => beside(Float(-0.0)).and_then(copy),
+            ValueOps::Fsub => beside(Float(0.0)).and_then(copy),
+            _ => None,
+        }
+    }
+
     ///maps args to numbered form
     fn map_args(&mut self, args: &Vec<String>) -> Vec<u32> {
         args.iter().map(|x| self.get_var(x)).collect()
@@ -232,6 +616,7 @@ impl LVNTable {
             Instruction::Constant {
                 pos, const_type, ..
             } => {
+                self.update_clobbers(&dest);
                 if let Some(num) = self.find_val(&exp) {
                     self.bind_var_to_num(&dest, num)
                 } else {
@@ -256,10 +641,17 @@ impl LVNTable {
                 op_type,
                 ..
             } => {
-                let table_entry = self.find_val(&exp);
+                //a value from an earlier block may no longer be held by any variable
+                let table_entry = self
+                    .find_val(&exp)
+                    .filter(|x| self.num_canonical.get(x).is_some_and(|x| !x.is_empty()));
                 // if the value is already in the table, emit an normal instruction
                 //handles special case where calls, alloc, ptradd, etc. should not be tabulated
-                if table_entry.is_none() || !is_value(&op) {
+                let numbered = match &exp.0 {
+                    OP::Value(op) => is_value(op),
+                    _ => true,
+                };
+                if table_entry.is_none() || !numbered {
                     let res = Instruction::Value {
                         dest: dest.clone(),
                         args: exp
@@ -335,10 +727,24 @@ fn last_def(code: &Vec<Code>) -> Vec<bool> {
     is_last_def
 }
 
-/// returns true if [op] performs an operation that can be a tabulated value
-fn is_value(op: &ValueOps) -> bool {
-    match op {
-        ValueOps::Call | ValueOps::Alloc | ValueOps::Load | ValueOps::PtrAdd => false,
-        _ => true,
-    }
+/// returns true if the arguments of [op] can be swapped
+pub(crate) fn is_commutative(op: &ValueOps) -> bool {
+    matches!(
+        op,
+        ValueOps::Add
+            | ValueOps::Mul
+            | ValueOps::Eq
+            | ValueOps::And
+            | ValueOps::Or
+            | ValueOps::Fadd
+            | ValueOps::Fmul
+            | ValueOps::Feq
+            | ValueOps::Ceq
+    )
+}
+
+/// returns true if [op] performs an operation that can be a tabulated value. calls are only
+/// tabulated when the callee is pure, see [OP::Call]
+pub(crate) fn is_value(op: &ValueOps) -> bool {
+    *op != ValueOps::Call && value_op_effects(op).is_pure()
 }
@@ -1,66 +1,501 @@
 use bril_opts::analysis::live_variable_analysis;
-use bril_opts::cfg::graph_from_function;
-use bril_opts::dominator::{dom_tree, dominator_analyis};
-use bril_opts::ssa::to_ssa;
-use bril_opts::{analysis, lvn, tdce};
-use bril_rs::load_program;
-use clap::Parser;
+use bril_opts::callgraph::{call_graph, debug_call_graph_string};
+use bril_opts::cfg::{graph_from_function, CFG};
+use bril_opts::dominator::{
+    control_dependence, dom_tree, dominator_analyis, post_dominator_analysis,
+};
+use bril_opts::dot::{render, DotOptions, Facts};
+use bril_opts::gvn::gvn;
+use bril_opts::load::load;
+use bril_opts::loops::find_loops;
+use bril_opts::pass::{Pipeline, PipelineError, ProgramPass, Registry, TryProgramPass};
+use bril_opts::profile::{collect, layout, taken_jumps, EdgeProfile};
+use bril_opts::report::function_report;
+use bril_opts::sccp::sccp;
+use bril_opts::simplify::{drop_unused_labels, simplify_cfg};
+use bril_opts::ssa::{from_ssa, to_ssa};
+use bril_opts::strength::strength_reduce;
+use bril_opts::utils::BasicBlock;
+use bril_opts::validate::{Divergence, Validator};
+use bril_opts::{adce, analysis, dse, inline, licm, lvn, pre, regalloc, simplify, tdce};
+use bril_rs::Program;
+use clap::{Parser, ValueEnum};
 use petgraph::dot::{Config, Dot};
 use petgraph::graph::NodeIndex;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args {
+    /// reads the program from this file instead of stdin
+    #[arg(long, short, value_name = "FILE")]
+    file: Option<PathBuf>,
+    /// reads the program in the text format instead of JSON
+    #[arg(long, short)]
+    text: bool,
+    /// format the program is printed in
+    #[arg(long, value_enum, default_value_t = Emit::Text)]
+    emit: Emit,
+    /// inlines calls to small functions, unrolling recursive calls one level
+    #[arg(long)]
+    inline: bool,
+    /// largest function, in instructions, that --inline copies into its callers
+    #[arg(long, default_value_t = 40)]
+    inline_threshold: usize,
     #[arg(long)]
     dce: bool,
+    /// local value numbering, or across extended basic blocks (--lvn=ebb)
+    #[arg(
+        long,
+        value_enum,
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "local"
+    )]
+    lvn: Option<Lvn>,
+    /// partial redundancy elimination by lazy code motion
+    #[arg(long)]
+    pre: bool,
+    /// loop-invariant code motion
+    #[arg(long)]
+    licm: bool,
+    /// removes stores that are never read and allocations whose memory is never read
+    #[arg(long)]
+    dse: bool,
+    /// aggressive dead code elimination over the control dependence graph
     #[arg(long)]
-    lvn: bool,
+    adce: bool,
+    /// merges straight-line blocks, threads jumps through empty blocks and drops unused labels
     #[arg(long)]
-    liveness: bool,
+    simplify: bool,
+    /// prints live variables per block, or per instruction with live ranges (--liveness=instr)
+    #[arg(
+        long,
+        value_enum,
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "block"
+    )]
+    liveness: Option<Liveness>,
+    /// prints the definitions reaching the start and end of each block
+    #[arg(long)]
+    reaching: bool,
     #[arg(long)]
     dom: bool,
+    /// prints the immediate post-dominator and control dependences of each block
+    #[arg(long)]
+    cdg: bool,
+    /// prints the CFG of each function in DOT format, with its loops
     #[arg(long)]
     cfg: bool,
+    /// writes the CFG of each function in DOT format to DIR/<function>.dot
+    #[arg(long, value_name = "DIR")]
+    dot: Option<PathBuf>,
+    /// analyses drawn on the CFG of --dot and --cfg, e.g. `dom-tree,liveness`
+    #[arg(long, value_enum, value_delimiter = ',')]
+    dot_overlay: Vec<Overlay>,
+    /// reports the natural loops of each function
+    #[arg(long)]
+    loops: bool,
+    /// prints the call graph of the program
+    #[arg(long)]
+    call_graph: bool,
     #[arg(long)]
     ssa: bool,
+    /// convert out of SSA after --ssa, lowering phis into copies
+    #[arg(long)]
+    from_ssa: bool,
+    /// sparse conditional constant propagation, runs on the SSA form
+    #[arg(long)]
+    sccp: bool,
+    /// global value numbering over the dominator tree, runs on the SSA form
+    #[arg(long)]
+    gvn: bool,
+    /// replaces multiplications by induction variables with additions, runs on the SSA form
+    #[arg(long)]
+    strength_reduce: bool,
+    /// renames variables to --registers registers, keeping the others in memory. runs last
+    #[arg(long)]
+    regalloc: bool,
+    /// number of registers --regalloc and the regalloc pass of --passes allocate
+    #[arg(long, default_value_t = 16)]
+    registers: usize,
+    /// lays out the blocks of every function by an edge profile, from running the program in
+    /// brilirs with these arguments to main once every other pass is done
+    #[arg(long, num_args = 0.., allow_negative_numbers = true, value_name = "ARG")]
+    profile: Option<Vec<String>>,
+    /// lays out the blocks by the edge profile in FILE instead of running the program
+    #[arg(long, value_name = "FILE", conflicts_with = "profile")]
+    load_profile: Option<PathBuf>,
+    /// writes the edge profile of --profile or --load-profile to FILE
+    #[arg(long, value_name = "FILE")]
+    save_profile: Option<PathBuf>,
+    /// seconds the run of --profile gets before profiling fails
+    #[arg(long, value_name = "SECS", default_value_t = 10, requires = "profile")]
+    profile_timeout: u64,
+    /// runs the given passes in order instead of the flags above, e.g. `lvn,dce,ssa,dce`.
+    /// `(lvn,dce)*` repeats a group until the program stops changing
+    #[arg(long, conflicts_with_all = [
+        "inline", "lvn", "pre", "licm", "dse", "adce", "dce", "simplify",
+        "ssa", "from_ssa", "sccp", "gvn", "strength_reduce", "regalloc",
+    ])]
+    passes: Option<Pipeline>,
+    /// prints the time and instruction counts of each pass of --passes to stderr
+    #[arg(long, requires = "passes")]
+    stats: bool,
+    /// runs the program in brilirs after every pass, with these arguments to main, and stops at
+    /// the first pass that changes its output
+    #[arg(long, num_args = 0.., allow_negative_numbers = true, value_name = "ARG")]
+    validate: Option<Vec<String>>,
+    /// seconds each run of --validate gets before it counts as not terminating
+    #[arg(long, value_name = "SECS", default_value_t = 10, requires = "validate")]
+    validate_timeout: u64,
+    /// how analyses are printed. `json` prints one document per function with its CFG,
+    /// liveness and dominators instead of the text of the analysis flags
+    #[arg(long, value_enum, default_value_t = Format::Text)]
+    format: Format,
+    /// writes the program to this file instead of stdout. with --format json the program is only
+    /// written here
+    #[arg(long, short, value_name = "FILE")]
+    output: Option<PathBuf>,
 }
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum Emit {
+    /// the text format of bril2txt
+    Text,
+    /// JSON, with the source position of each instruction that has one
+    Json,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum Overlay {
+    /// edges from each block to the blocks it immediately dominates
+    DomTree,
+    /// loop headers, back edges and the nesting depth of each block
+    Loops,
+    /// variables live at the start and end of each block
+    Liveness,
+    /// definitions reaching the start and end of each block
+    Reaching,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum Format {
+    Text,
+    Json,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum Lvn {
+    /// each block on its own
+    Local,
+    /// chains of blocks with a single predecessor, sharing the values of the blocks before them
+    Ebb,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum Liveness {
+    /// sets at the start and end of each block
+    Block,
+    /// sets next to every instruction, then the live range of each variable
+    Instr,
+}
+
+impl Args {
+    /// passes that need the program in SSA form
+    fn needs_ssa(&self) -> bool {
+        self.ssa || self.from_ssa || self.sccp || self.gvn || self.strength_reduce
+    }
+}
+
 fn main() {
     std::env::set_var("RUST_BACKTRACE", "1");
 
-    let mut prog = load_program();
     let args = Args::parse();
+    let mut prog = read_program(&args);
+
+    let validator = args
+        .validate
+        .as_ref()
+        .map(|x| Validator::new(&prog, x.clone(), Duration::from_secs(args.validate_timeout)));
+    if validator.as_ref().is_some_and(|x| x.expected.timed_out) {
+        eprintln!(
+            "warning: the program didn't finish within {}s, nothing is validated",
+            args.validate_timeout
+        );
+    }
+    let mut last = validator.as_ref().map(|x| x.expected.clone());
+    let mut check = |pass: &str, prog: &Program| -> Result<(), Divergence> {
+        if let Some(run) = validator
+            .as_ref()
+            .map(|x| x.check(pass, prog))
+            .transpose()?
+        {
+            last = run;
+        }
+        Ok(())
+    };
 
-    if args.lvn {
-        prog = lvn::lvn(prog);
+    if let Some(pipeline) = &args.passes {
+        let mut registry = Registry::builtin();
+        let threshold = args.inline_threshold;
+        registry.register(ProgramPass::new("inline", move |x| {
+            inline::inline(x, threshold)
+        }));
+        let registers = args.registers;
+        registry.register(TryProgramPass::new("regalloc", move |x| {
+            regalloc::regalloc(x, registers)
+        }));
+        prog = match pipeline.run_checked(&registry, prog, args.stats, &mut check) {
+            Ok(prog) => prog,
+            Err(PipelineError::Pass(e)) => {
+                eprintln!("error: {}", e);
+                std::process::exit(2);
+            }
+            Err(PipelineError::Check(e)) => diverged(Err(e)),
+        };
+    }
+    if args.inline {
+        prog = inline::inline(prog, args.inline_threshold);
+        diverged(check("inline", &prog));
+    }
+    match args.lvn {
+        Some(Lvn::Local) => prog = lvn::lvn(prog),
+        Some(Lvn::Ebb) => prog = lvn::ebb_lvn(prog),
+        None => (),
+    }
+    if args.lvn.is_some() {
+        diverged(check("lvn", &prog));
+    }
+    if args.pre {
+        prog = pre::pre(prog);
+        diverged(check("pre", &prog));
+    }
+    if args.licm {
+        prog = licm::licm(prog);
+        diverged(check("licm", &prog));
+    }
+    if args.dse {
+        prog = dse::dse(prog);
+        diverged(check("dse", &prog));
+    }
+    if args.adce {
+        prog = adce::adce(prog);
+        diverged(check("adce", &prog));
     }
     if args.dce {
         prog = tdce::local_pass(prog);
         prog = tdce::global_pass(prog);
+        diverged(check("dce", &prog));
+    }
+    if args.simplify {
+        prog = simplify::simplify(prog);
+        diverged(check("simplify", &prog));
     }
 
-    if args.liveness {
-        analysis::live_variable_debug(&prog);
+    let json = args.format == Format::Json;
+    if !json {
+        match args.liveness {
+            Some(Liveness::Block) => analysis::live_variable_debug(&prog),
+            Some(Liveness::Instr) => analysis::live_instruction_debug(&prog),
+            None => (),
+        }
+        if args.reaching {
+            analysis::reaching_definitions_debug(&prog);
+        }
+        if args.call_graph {
+            println!("{}", debug_call_graph_string(&call_graph(&prog)));
+        }
+    }
+    if args.needs_ssa() {
+        diverged(conv_to_ssa(&mut prog, &args, &mut check));
+    }
+    if args.regalloc {
+        prog = allocated(regalloc::regalloc(prog, args.registers));
+        diverged(check("regalloc", &prog));
     }
-    if args.ssa {
-        conv_to_ssa(&mut prog);
-        println!("{}", prog);
+    if let Some(profile) = edge_profile(&args, &prog) {
+        let before = taken_jumps(&prog, &profile);
+        prog = layout(prog, &profile);
+        diverged(check("layout", &prog));
+        eprintln!(
+            "taken jumps: {} -> {}",
+            before,
+            taken_jumps(&prog, &profile)
+        );
     }
-    if args.liveness || args.dom || args.cfg || args.ssa {
+    if let (Some(validator), Some(last)) = (&validator, &last) {
+        eprintln!("{}", validator.summary(last));
+    }
+    let analyses = args.liveness.is_some() || args.dom || args.cdg || args.cfg || args.loops;
+    if let Some(path) = &args.output {
+        if let Err(e) = std::fs::write(path, format!("{}\n", emit(&args, &prog))) {
+            eprintln!("error: can't write {}: {}", path.display(), e);
+            std::process::exit(2);
+        }
+    } else if !json && (args.needs_ssa() || !(analyses || args.call_graph || args.reaching)) {
+        println!("{}", emit(&args, &prog));
+    }
+    if json {
+        for func in &prog.functions {
+            println!("{}", serde_json::to_string(&function_report(func)).unwrap());
+        }
+    } else if analyses {
         for func in &prog.functions {
             function_analysis(&args, func);
         }
-    } else {
-        println!("{}", prog);
     }
+    if let Some(dir) = &args.dot {
+        if let Err(e) = write_dot(&args, &prog, dir) {
+            eprintln!("error: can't write to {}: {}", dir.display(), e);
+            std::process::exit(2);
+        }
+    }
+}
+
+/// the result of register allocation, or exits if there weren't enough registers
+fn allocated(result: Result<Program, String>) -> Program {
+    result.unwrap_or_else(|e| {
+        eprintln!("error: {}", e);
+        std::process::exit(2);
+    })
+}
+
+/// the edge profile of --profile or --load-profile for [prog], written to --save-profile.
+/// exits if it can't be collected, read or written
+fn edge_profile(args: &Args, prog: &Program) -> Option<EdgeProfile> {
+    let fail = |e: String| -> ! {
+        eprintln!("error: {}", e);
+        std::process::exit(2);
+    };
+    let profile = match (&args.profile, &args.load_profile) {
+        (Some(run_args), _) => {
+            let budget = Duration::from_secs(args.profile_timeout);
+            collect(prog, run_args, budget).unwrap_or_else(|e| fail(e))
+        }
+        (None, Some(path)) => std::fs::read_to_string(path)
+            .map_err(|e| e.to_string())
+            .and_then(|x| serde_json::from_str(&x).map_err(|e| e.to_string()))
+            .unwrap_or_else(|e| fail(format!("can't read {}: {}", path.display(), e))),
+        (None, None) => return None,
+    };
+    if let Some(path) = &args.save_profile {
+        if let Err(e) = std::fs::write(path, serde_json::to_string(&profile).unwrap()) {
+            fail(format!("can't write {}: {}", path.display(), e));
+        }
+    }
+    Some(profile)
+}
+
+/// the program of --file or stdin, or exits with the place it is malformed
+fn read_program(args: &Args) -> Program {
+    let source = match &args.file {
+        Some(path) => std::fs::read_to_string(path),
+        None => std::io::read_to_string(std::io::stdin()),
+    };
+    let source = source.unwrap_or_else(|e| {
+        eprintln!("error: can't read the program: {}", e);
+        std::process::exit(2);
+    });
+    let src = args.file.as_ref().map(|x| x.display().to_string());
+    load(&source, args.text, src).unwrap_or_else(|e| {
+        eprintln!("error: {}", e);
+        let excerpt = e.excerpt(&source);
+        if !excerpt.is_empty() {
+            eprintln!("{}", excerpt);
+        }
+        std::process::exit(2);
+    })
+}
+
+/// [prog] in the format of --emit
+fn emit(args: &Args, prog: &Program) -> String {
+    match args.emit {
+        Emit::Text => prog.to_string(),
+        Emit::Json => serde_json::to_string(prog).unwrap(),
+    }
+}
+
+/// the overlays of --dot-overlay for [cfg]
+fn dot_options(args: &Args, cfg: &CFG<BasicBlock>) -> DotOptions {
+    let mut options = DotOptions::default();
+    for overlay in &args.dot_overlay {
+        match overlay {
+            Overlay::DomTree => options.dom_tree = true,
+            Overlay::Loops => options.loops = true,
+            Overlay::Liveness => options.facts.extend(Facts::liveness(cfg)),
+            Overlay::Reaching => options.facts.extend(Facts::reaching(cfg)),
+        }
+    }
+    options
 }
 
-fn conv_to_ssa(prog: &mut bril_rs::Program) {
-    for func in &mut prog.functions {
+/// writes the CFG of every function of [prog] to its own file in [dir]
+fn write_dot(args: &Args, prog: &Program, dir: &Path) -> std::io::Result<()> {
+    std::fs::create_dir_all(dir)?;
+    for func in &prog.functions {
         let cfg = graph_from_function(func);
-        let mut ssa = to_ssa(&cfg);
-        //ssa.debug_cfg();
-        func.instrs = ssa.flatten();
+        let dot = render(&func.name, &cfg, &dot_options(args, &cfg));
+        std::fs::write(dir.join(format!("{}.dot", func.name)), dot)?;
+    }
+    Ok(())
+}
+
+/// the result of a pass checked by --validate, or exits with the divergence
+fn diverged<T>(result: Result<T, Divergence>) -> T {
+    match result {
+        Ok(x) => x,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// the SSA passes of [args] on every function, [check]ed after each of them
+fn conv_to_ssa(
+    prog: &mut Program,
+    args: &Args,
+    check: &mut dyn FnMut(&str, &Program) -> Result<(), Divergence>,
+) -> Result<(), Divergence> {
+    let mut cfgs = prog
+        .functions
+        .iter()
+        .map(|x| to_ssa(&graph_from_function(x)))
+        .collect::<Vec<_>>();
+    //the program with each function replaced by its CFG
+    let mut stage = |name: &str, cfgs: &[CFG<BasicBlock>]| {
+        for (func, cfg) in prog.functions.iter_mut().zip(cfgs) {
+            func.instrs = cfg.clone().flatten();
+            if args.simplify {
+                drop_unused_labels(&mut func.instrs);
+            }
+        }
+        check(name, prog)
+    };
+    stage("ssa", &cfgs)?;
+    let passes: [(bool, &str, fn(&CFG<BasicBlock>) -> CFG<BasicBlock>); 3] = [
+        (args.sccp, "sccp", sccp),
+        (args.gvn, "gvn", gvn),
+        (args.strength_reduce, "strength-reduce", strength_reduce),
+    ];
+    for (enabled, name, pass) in passes {
+        if enabled {
+            cfgs = cfgs.iter().map(pass).collect();
+            stage(name, &cfgs)?;
+        }
     }
+    if args.simplify {
+        cfgs.iter_mut().for_each(simplify_cfg);
+        stage("simplify", &cfgs)?;
+    }
+    if args.from_ssa {
+        cfgs = cfgs.iter().map(from_ssa).collect();
+        stage("from-ssa", &cfgs)?;
+    }
+    Ok(())
 }
 
 fn function_analysis(args: &Args, func: &bril_rs::Function) {
@@ -75,13 +510,49 @@ fn function_analysis(args: &Args, func: &bril_rs::Function) {
     // }
 
     let (entry, exit) = live_variable_analysis(&cfg);
+    let forest = find_loops(&cfg);
     if args.cfg {
+        let options = DotOptions {
+            loops: true,
+            ..dot_options(args, &cfg)
+        };
         println!("@{} CFG", func.name);
-        cfg.debug_cfg();
-        println!("");
+        println!("{}", render(&func.name, &cfg, &options));
+    }
+
+    if args.loops {
+        let label = |x: &NodeIndex| cfg.graph.node_weight(*x).unwrap().label.clone().unwrap();
+        println!("@{} Loops", func.name);
+        for l in &forest.loops {
+            println!("{:?}:", label(&l.header));
+            println!("Depth: {}", l.depth);
+            println!(
+                "Parent: {:?}",
+                l.parent.map(|x| label(&forest.loops[x].header))
+            );
+            println!("Body: {:?}", {
+                let mut body = l.body.iter().map(label).collect::<Vec<_>>();
+                body.sort();
+                body
+            });
+            println!(
+                "Latches: {:?}",
+                l.latches.iter().map(label).collect::<Vec<_>>()
+            );
+            println!("Preheader: {:?}", l.preheader.as_ref().map(label));
+            println!("Exits: {:?}", l.exits.iter().map(label).collect::<Vec<_>>());
+            println!(
+                "Exiting Edges: {:?}",
+                l.exiting_edges
+                    .iter()
+                    .map(|(from, to)| (label(from), label(to)))
+                    .collect::<Vec<_>>()
+            );
+            println!();
+        }
     }
 
-    if args.liveness {
+    if args.liveness == Some(Liveness::Block) {
         println!("@{} Liveness Analysis", func.name);
         for i in 0..cfg.graph.node_count() {
             println!(
@@ -136,4 +607,28 @@ fn function_analysis(args: &Args, func: &bril_rs::Function) {
         let dom_tree = dom_tree(&dominators, &cfg);
         println!("{:?}", Dot::with_config(&dom_tree, &[Config::EdgeNoLabel]));
     }
+
+    if args.cdg {
+        let (post_dominators, exit) = post_dominator_analysis(&cfg);
+        let cdg = control_dependence(&cfg, &post_dominators);
+        let label = |x: NodeIndex| match x == exit {
+            true => "exit".to_string(),
+            false => cfg.graph.node_weight(x).unwrap().label.clone().unwrap(),
+        };
+        println!("@{} Control Dependence", func.name);
+        for node in cfg.graph.node_indices() {
+            println!("{:?}:", label(node));
+            println!(
+                "Post Dominator: {:?}",
+                post_dominators.immediate_dominator(node).map(label)
+            );
+            println!(
+                "Control Dependences: {:?}",
+                cdg.neighbors_directed(node, petgraph::Direction::Incoming)
+                    .map(label)
+                    .collect::<Vec<_>>()
+            );
+            println!();
+        }
+    }
 }
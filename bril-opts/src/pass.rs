@@ -0,0 +1,506 @@
+use std::convert::Infallible;
+use std::fmt::Display;
+use std::iter::Peekable;
+use std::str::FromStr;
+use std::time::Instant;
+use std::vec::IntoIter;
+
+use bril_rs::{Code, Function, Program};
+
+use crate::cfg::{graph_from_function, CFG};
+use crate::gvn::gvn;
+use crate::sccp::sccp;
+use crate::ssa::{from_ssa, lift_phis, remove_dead_phis, to_ssa};
+use crate::strength::strength_reduce;
+use crate::utils::BasicBlock;
+use crate::{adce, dse, inline, licm, lvn, pre, regalloc, simplify, tdce};
+
+/// a transformation that can run in a [Pipeline]. a pass implements [Pass::run] if it needs the
+/// whole program, or [Pass::run_function] if each function can be transformed on its own. a
+/// pass that can fail implements [Pass::try_run], which is what a [Pipeline] calls
+pub trait Pass {
+    /// name of the pass in `--passes`
+    fn name(&self) -> &str;
+
+    fn try_run(&self, prog: Program) -> Result<Program, String> {
+        Ok(self.run(prog))
+    }
+
+    fn run(&self, prog: Program) -> Program {
+        Program {
+            functions: prog
+                .functions
+                .into_iter()
+                .map(|x| self.run_function(x))
+                .collect(),
+            ..prog
+        }
+    }
+
+    fn run_function(&self, func: Function) -> Function {
+        func
+    }
+}
+
+/// a [Pass] over whole programs, from its name and a function
+pub struct ProgramPass<F> {
+    name: String,
+    run: F,
+}
+
+impl<F: Fn(Program) -> Program> ProgramPass<F> {
+    pub fn new(name: &str, run: F) -> Self {
+        ProgramPass {
+            name: name.to_string(),
+            run,
+        }
+    }
+}
+
+impl<F: Fn(Program) -> Program> Pass for ProgramPass<F> {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn run(&self, prog: Program) -> Program {
+        (self.run)(prog)
+    }
+}
+
+/// a [Pass] over whole programs that can fail, from its name and a function. [Pass::run] panics
+/// with the error, a [Pipeline] returns it instead
+pub struct TryProgramPass<F> {
+    name: String,
+    run: F,
+}
+
+impl<F: Fn(Program) -> Result<Program, String>> TryProgramPass<F> {
+    pub fn new(name: &str, run: F) -> Self {
+        TryProgramPass {
+            name: name.to_string(),
+            run,
+        }
+    }
+}
+
+impl<F: Fn(Program) -> Result<Program, String>> Pass for TryProgramPass<F> {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn try_run(&self, prog: Program) -> Result<Program, String> {
+        (self.run)(prog)
+    }
+
+    fn run(&self, prog: Program) -> Program {
+        self.try_run(prog).unwrap_or_else(|e| panic!("{}", e))
+    }
+}
+
+/// a [Pass] over each function on its own, from its name and a function
+pub struct FunctionPass<F> {
+    name: String,
+    run: F,
+}
+
+impl<F: Fn(Function) -> Function> FunctionPass<F> {
+    pub fn new(name: &str, run: F) -> Self {
+        FunctionPass {
+            name: name.to_string(),
+            run,
+        }
+    }
+}
+
+impl<F: Fn(Function) -> Function> Pass for FunctionPass<F> {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn run_function(&self, func: Function) -> Function {
+        (self.run)(func)
+    }
+}
+
+/// the CFG of [func] in SSA form, converting it unless it already has phis. phis that nothing
+/// reads are dropped, they may lack a source for some predecessor, which brilirs rejects
+fn ssa_form(func: &Function) -> CFG<BasicBlock> {
+    let mut cfg = graph_from_function(func);
+    if lift_phis(&mut cfg) {
+        cfg
+    } else {
+        let mut ssa = to_ssa(&cfg);
+        remove_dead_phis(&mut ssa);
+        ssa
+    }
+}
+
+/// a pass over the SSA form of each function, the result stays in SSA form
+fn ssa_pass(name: &str, run: fn(&CFG<BasicBlock>) -> CFG<BasicBlock>) -> impl Pass {
+    FunctionPass::new(name, move |func: Function| Function {
+        instrs: run(&ssa_form(&func)).flatten(),
+        ..func
+    })
+}
+
+/// the passes a [Pipeline] can run, by name
+#[derive(Default)]
+pub struct Registry {
+    passes: Vec<Box<dyn Pass>>,
+}
+
+impl Registry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// every pass of this crate
+    pub fn builtin() -> Self {
+        let mut registry = Registry::new();
+        registry.register(ProgramPass::new("inline", |x| inline::inline(x, 40)));
+        registry.register(ProgramPass::new("lvn", lvn::lvn));
+        registry.register(ProgramPass::new("lvn-ebb", lvn::ebb_lvn));
+        registry.register(ProgramPass::new("pre", pre::pre));
+        registry.register(ProgramPass::new("licm", licm::licm));
+        registry.register(ProgramPass::new("dse", dse::dse));
+        registry.register(ProgramPass::new("adce", adce::adce));
+        registry.register(ProgramPass::new("dce", |x| {
+            tdce::global_pass(tdce::local_pass(x))
+        }));
+        registry.register(ProgramPass::new("simplify", simplify::simplify));
+        registry.register(ssa_pass("ssa", |x| x.clone()));
+        registry.register(ssa_pass("sccp", sccp));
+        registry.register(ssa_pass("gvn", gvn));
+        registry.register(ssa_pass("strength-reduce", strength_reduce));
+        registry.register(FunctionPass::new("from-ssa", |func: Function| {
+            let mut cfg = graph_from_function(&func);
+            if !lift_phis(&mut cfg) {
+                return func;
+            }
+            Function {
+                instrs: from_ssa(&cfg).flatten(),
+                ..func
+            }
+        }));
+        registry.register(TryProgramPass::new("regalloc", |x| {
+            regalloc::regalloc(x, 16)
+        }));
+        registry
+    }
+
+    /// adds [pass], replacing the pass with the same name
+    pub fn register(&mut self, pass: impl Pass + 'static) {
+        self.passes.retain(|x| x.name() != pass.name());
+        self.passes.push(Box::new(pass));
+    }
+
+    pub fn get(&self, name: &str) -> Option<&dyn Pass> {
+        self.passes
+            .iter()
+            .find(|x| x.name() == name)
+            .map(|x| x.as_ref())
+    }
+
+    pub fn names(&self) -> Vec<&str> {
+        self.passes.iter().map(|x| x.name()).collect()
+    }
+}
+
+/// most times a fixpoint group runs, in case its passes keep changing the program
+pub const MAX_ITERATIONS: usize = 16;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Step {
+    Pass(String),
+    /// passes in parentheses
+    Group(Vec<Step>),
+    /// passes repeated until the program stops changing, written `(...)*`
+    Fixpoint(Vec<Step>),
+}
+
+/// why a [Pipeline] stopped
+#[derive(Debug, Clone)]
+pub enum PipelineError<E> {
+    /// a pass that isn't in the registry, or that failed
+    Pass(String),
+    /// the check of a pass returned an error
+    Check(E),
+}
+
+impl<E: Display> Display for PipelineError<E> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PipelineError::Pass(e) => write!(f, "{}", e),
+            PipelineError::Check(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+/// an ordered list of passes, such as `lvn,dce,ssa,dce` or `inline,(lvn,dce)*`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Pipeline {
+    pub steps: Vec<Step>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    Name(String),
+    Open,
+    Close,
+    Comma,
+    Star,
+}
+
+fn tokenize(s: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = vec![];
+    let mut chars = s.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '(' => tokens.push(Token::Open),
+            ')' => tokens.push(Token::Close),
+            ',' => tokens.push(Token::Comma),
+            '*' => tokens.push(Token::Star),
+            c if c.is_whitespace() => (),
+            c if c.is_alphanumeric() || c == '-' || c == '_' => {
+                let mut name = c.to_string();
+                while let Some(c) = chars.next_if(|c| c.is_alphanumeric() || *c == '-' || *c == '_')
+                {
+                    name.push(c);
+                }
+                tokens.push(Token::Name(name));
+            }
+            c => return Err(format!("unexpected `{}` in pipeline", c)),
+        }
+    }
+    Ok(tokens)
+}
+
+fn parse_steps(tokens: &mut Peekable<IntoIter<Token>>) -> Result<Vec<Step>, String> {
+    let mut steps = vec![parse_step(tokens)?];
+    while tokens.next_if_eq(&Token::Comma).is_some() {
+        steps.push(parse_step(tokens)?);
+    }
+    Ok(steps)
+}
+
+fn parse_step(tokens: &mut Peekable<IntoIter<Token>>) -> Result<Step, String> {
+    let steps = match tokens.next() {
+        Some(Token::Name(name)) => vec![Step::Pass(name)],
+        Some(Token::Open) => {
+            let steps = parse_steps(tokens)?;
+            if tokens.next() != Some(Token::Close) {
+                return Err("expected `)` in pipeline".to_string());
+            }
+            steps
+        }
+        Some(token) => return Err(format!("unexpected {:?} in pipeline", token)),
+        None => return Err("expected a pass in pipeline".to_string()),
+    };
+    Ok(match (tokens.next_if_eq(&Token::Star), steps.as_slice()) {
+        (Some(_), _) => Step::Fixpoint(steps),
+        (None, [Step::Pass(_)]) => steps.into_iter().next().unwrap(),
+        (None, _) => Step::Group(steps),
+    })
+}
+
+impl FromStr for Pipeline {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut tokens = tokenize(s)?.into_iter().peekable();
+        let steps = parse_steps(&mut tokens)?;
+        match tokens.next() {
+            Some(token) => Err(format!("unexpected {:?} in pipeline", token)),
+            None => Ok(Pipeline { steps }),
+        }
+    }
+}
+
+/// number of instructions in [prog], not counting labels
+pub fn instruction_count(prog: &Program) -> usize {
+    prog.functions
+        .iter()
+        .flat_map(|x| &x.instrs)
+        .filter(|x| matches!(x, Code::Instruction(_)))
+        .count()
+}
+
+impl Pipeline {
+    /// returns an error naming the first pass that isn't in [registry]
+    pub fn check(&self, registry: &Registry) -> Result<(), String> {
+        fn check_steps(steps: &[Step], registry: &Registry) -> Result<(), String> {
+            for step in steps {
+                match step {
+                    Step::Pass(name) if registry.get(name).is_none() => {
+                        return Err(format!(
+                            "unknown pass `{}`, expected one of {}",
+                            name,
+                            registry.names().join(", ")
+                        ))
+                    }
+                    Step::Pass(_) => (),
+                    Step::Group(steps) | Step::Fixpoint(steps) => check_steps(steps, registry)?,
+                }
+            }
+            Ok(())
+        }
+        check_steps(&self.steps, registry)
+    }
+
+    /// runs the passes on [prog] in order. returns an error before running anything if a pass
+    /// isn't in [registry], see [Pipeline::check], or the error of the first pass that fails.
+    /// with [stats], prints the time each pass took and the instructions before and after to
+    /// stderr
+    pub fn run(&self, registry: &Registry, prog: Program, stats: bool) -> Result<Program, String> {
+        self.run_checked(registry, prog, stats, &mut |_, _| Ok::<_, Infallible>(()))
+            .map_err(|e| match e {
+                PipelineError::Pass(e) => e,
+                PipelineError::Check(e) => match e {},
+            })
+    }
+
+    /// like [Pipeline::run], but calls [check] with the name of each pass and the program it
+    /// produced, stopping at the first error
+    pub fn run_checked<E>(
+        &self,
+        registry: &Registry,
+        prog: Program,
+        stats: bool,
+        check: &mut dyn FnMut(&str, &Program) -> Result<(), E>,
+    ) -> Result<Program, PipelineError<E>> {
+        self.check(registry).map_err(PipelineError::Pass)?;
+        run_steps(&self.steps, registry, prog, stats, check)
+    }
+}
+
+fn run_steps<E>(
+    steps: &[Step],
+    registry: &Registry,
+    mut prog: Program,
+    stats: bool,
+    check: &mut dyn FnMut(&str, &Program) -> Result<(), E>,
+) -> Result<Program, PipelineError<E>> {
+    for step in steps {
+        prog = match step {
+            Step::Pass(name) => {
+                let pass = registry
+                    .get(name)
+                    .ok_or_else(|| PipelineError::Pass(format!("unknown pass `{}`", name)))?;
+                let before = instruction_count(&prog);
+                let start = Instant::now();
+                let prog = pass.try_run(prog).map_err(PipelineError::Pass)?;
+                if stats {
+                    eprintln!(
+                        "{:<16} {:>12.3?} {:>8} -> {:<8} instructions",
+                        name,
+                        start.elapsed(),
+                        before,
+                        instruction_count(&prog)
+                    );
+                }
+                check(name, &prog).map_err(PipelineError::Check)?;
+                prog
+            }
+            Step::Group(steps) => run_steps(steps, registry, prog, stats, check)?,
+            Step::Fixpoint(steps) => {
+                //compared as text, which leaves out source positions
+                let mut text = prog.to_string();
+                for i in 1..=MAX_ITERATIONS {
+                    prog = run_steps(steps, registry, prog, stats, check)?;
+                    let next = prog.to_string();
+                    if next == text {
+                        if stats {
+                            eprintln!("fixpoint after {} iterations", i);
+                        }
+                        break;
+                    }
+                    text = next;
+                }
+                prog
+            }
+        };
+    }
+    Ok(prog)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pass(name: &str) -> Step {
+        Step::Pass(name.to_string())
+    }
+
+    #[test]
+    fn parses_passes_in_order() {
+        assert_eq!(
+            "lvn, dce,ssa".parse(),
+            Ok(Pipeline {
+                steps: vec![pass("lvn"), pass("dce"), pass("ssa")]
+            })
+        );
+    }
+
+    #[test]
+    fn parses_nested_groups_and_fixpoints() {
+        assert_eq!(
+            "inline,((lvn,dce)*,simplify)*,(ssa)".parse(),
+            Ok(Pipeline {
+                steps: vec![
+                    pass("inline"),
+                    Step::Fixpoint(vec![
+                        Step::Fixpoint(vec![pass("lvn"), pass("dce")]),
+                        pass("simplify"),
+                    ]),
+                    pass("ssa"),
+                ]
+            })
+        );
+        assert_eq!(
+            "(lvn,(dce,adce))".parse(),
+            Ok(Pipeline {
+                steps: vec![Step::Group(vec![
+                    pass("lvn"),
+                    Step::Group(vec![pass("dce"), pass("adce")]),
+                ])]
+            })
+        );
+        assert_eq!(
+            "dce*".parse(),
+            Ok(Pipeline {
+                steps: vec![Step::Fixpoint(vec![pass("dce")])]
+            })
+        );
+    }
+
+    #[test]
+    fn rejects_trailing_and_empty_steps() {
+        let expected = Err("expected a pass in pipeline".to_string());
+        assert_eq!("lvn,dce,".parse::<Pipeline>(), expected);
+        assert_eq!("".parse::<Pipeline>(), expected);
+        assert_eq!(
+            "lvn,,dce".parse::<Pipeline>(),
+            Err("unexpected Comma in pipeline".to_string())
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_tokens() {
+        assert_eq!(
+            "lvn;dce".parse::<Pipeline>(),
+            Err("unexpected `;` in pipeline".to_string())
+        );
+        assert_eq!(
+            "(lvn,dce".parse::<Pipeline>(),
+            Err("expected `)` in pipeline".to_string())
+        );
+        assert_eq!(
+            "lvn)".parse::<Pipeline>(),
+            Err("unexpected Close in pipeline".to_string())
+        );
+        assert_eq!(
+            "*lvn".parse::<Pipeline>(),
+            Err("unexpected Star in pipeline".to_string())
+        );
+    }
+}
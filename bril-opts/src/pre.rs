@@ -0,0 +1,447 @@
+use std::collections::{HashMap, HashSet};
+
+use bril_rs::{Code, Function, Instruction, Program, Type, ValueOps};
+use petgraph::graph::NodeIndex;
+
+use crate::analysis::live_variable_analysis;
+use crate::cfg::{graph_from_function, Dir, CFG};
+use crate::dataflow::{intersection, solve, union, Analysis};
+use crate::lvn::is_value;
+use crate::utils::{BasicBlock, CFGNode};
+
+/// an operation and its arguments, expressions are matched by name so `add a b` is only
+/// redundant with another `add a b`
+type Expr = (ValueOps, Vec<String>);
+
+/// partial redundancy elimination by lazy code motion. every computation is moved to the latest
+/// point where it is still computed at most once on each path, so expressions that are computed
+/// on some paths into a block and again inside it are only computed once
+pub fn pre(prog: Program) -> Program {
+    Program {
+        functions: prog.functions.into_iter().map(pre_function).collect(),
+        imports: prog.imports,
+    }
+}
+
+fn pre_function(func: Function) -> Function {
+    let mut cfg = graph_from_function(&func);
+    pre_cfg(&mut cfg);
+    Function {
+        instrs: cfg.flatten(),
+        ..func
+    }
+}
+
+/// returns true if computing [op] earlier than the program would is safe, it has no side effects
+/// and can't fail
+fn is_candidate(op: &ValueOps) -> bool {
+    is_value(op)
+        && !matches!(
+            op,
+            ValueOps::Id | ValueOps::Phi | ValueOps::Div | ValueOps::Int2char
+        )
+}
+
+/// expression computed by [code], if it is a candidate for PRE
+fn expr(code: &Code) -> Option<(Expr, &String, &Type)> {
+    match code {
+        Code::Instruction(Instruction::Value {
+            op,
+            args,
+            dest,
+            op_type,
+            ..
+        }) if is_candidate(op) => Some(((*op, args.clone()), dest, op_type)),
+        _ => None,
+    }
+}
+
+/// runs PRE on [cfg]. edges into blocks with several predecessors are split first so that
+/// computations can be placed on a single edge, splits that stay empty are removed again
+pub fn pre_cfg(cfg: &mut CFG<BasicBlock>) {
+    cfg.delete_unreachable();
+    let mut labels = cfg
+        .graph
+        .node_weights()
+        .filter_map(|x| x.label.clone())
+        .collect::<HashSet<_>>();
+    let joins = cfg
+        .graph
+        .node_indices()
+        .filter(|x| {
+            cfg.graph
+                .neighbors_directed(*x, petgraph::Direction::Incoming)
+                .count()
+                > 1
+        })
+        .collect::<Vec<_>>();
+    let mut split = HashSet::new();
+    for to in joins {
+        let mut preds = cfg
+            .graph
+            .neighbors_directed(to, petgraph::Direction::Incoming)
+            .collect::<Vec<_>>();
+        preds.sort();
+        preds.dedup();
+        for from in preds {
+            split.insert(cfg.split_edge(from, to, "_PRE_E", &mut labels));
+        }
+    }
+
+    let mut types = HashMap::new();
+    let mut universe = HashSet::new();
+    for block in cfg.graph.node_weights() {
+        for (e, _, op_type) in block.instructions.iter().filter_map(expr) {
+            types.insert(e.clone(), op_type.clone());
+            universe.insert(e);
+        }
+    }
+    if universe.is_empty() {
+        remove_empty_splits(cfg, &split);
+        return;
+    }
+
+    //expressions computed in each block before any of their arguments are assigned, and those
+    //with an argument assigned in the block
+    let (e_use, e_kill): (Vec<_>, Vec<_>) = cfg
+        .graph
+        .node_weights()
+        .map(|block| {
+            let mut assigned = HashSet::<String>::new();
+            let mut e_use = HashSet::new();
+            for code in &block.instructions {
+                if let Some((e, _, _)) = expr(code) {
+                    if e.1.iter().all(|x| !assigned.contains(x)) {
+                        e_use.insert(e);
+                    }
+                }
+                assigned.extend(code.defs());
+            }
+            assigned.extend(block.defs.iter().cloned());
+            let e_kill = universe
+                .iter()
+                .filter(|(_, args)| args.iter().any(|x| assigned.contains(x)))
+                .cloned()
+                .collect::<HashSet<_>>();
+            (e_use, e_kill)
+        })
+        .unzip();
+
+    //expressions computed on every path from the start of a block before their arguments change
+    let (anticipated, _) = solve(
+        cfg,
+        &Analysis {
+            direction: Dir::Backward,
+            top: universe.clone(),
+            boundary: HashSet::new(),
+            meet: intersection,
+            transfer: |node: NodeIndex, _: &BasicBlock, out: &HashSet<Expr>| {
+                &e_use[node.index()] | &(out - &e_kill[node.index()])
+            },
+        },
+    );
+    //expressions that are available, or would be once they are computed wherever anticipated
+    let (available, _) = solve(
+        cfg,
+        &Analysis {
+            direction: Dir::Forward,
+            top: universe.clone(),
+            boundary: HashSet::new(),
+            meet: intersection,
+            transfer: |node: NodeIndex, _: &BasicBlock, available: &HashSet<Expr>| {
+                &(&anticipated[node.index()] | available) - &e_kill[node.index()]
+            },
+        },
+    );
+    let earliest = cfg
+        .graph
+        .node_indices()
+        .map(|node| &anticipated[node.index()] - &available[node.index()])
+        .collect::<Vec<_>>();
+    //expressions whose computation can still be moved down past the start of a block
+    let (postponable, _) = solve(
+        cfg,
+        &Analysis {
+            direction: Dir::Forward,
+            top: universe.clone(),
+            boundary: HashSet::new(),
+            meet: intersection,
+            transfer: |node: NodeIndex, _: &BasicBlock, postponable: &HashSet<Expr>| {
+                &(&earliest[node.index()] | postponable) - &e_use[node.index()]
+            },
+        },
+    );
+    //an expression is placed in a block if it can't be postponed into all of its successors
+    let placeable = |node: NodeIndex| &earliest[node.index()] | &postponable[node.index()];
+    let latest = cfg
+        .graph
+        .node_indices()
+        .map(|node| {
+            let everywhere = cfg
+                .graph
+                .neighbors(node)
+                .fold(universe.clone(), |mut acc, x| {
+                    intersection(&mut acc, &placeable(x));
+                    acc
+                });
+            let keep = &e_use[node.index()] | &(&universe - &everywhere);
+            &placeable(node) & &keep
+        })
+        .collect::<Vec<_>>();
+    //expressions whose value is read after the end of a block
+    let (_, used) = solve(
+        cfg,
+        &Analysis {
+            direction: Dir::Backward,
+            top: HashSet::new(),
+            boundary: HashSet::new(),
+            meet: union,
+            transfer: |node: NodeIndex, _: &BasicBlock, used: &HashSet<Expr>| {
+                &(&e_use[node.index()] | used) - &latest[node.index()]
+            },
+        },
+    );
+
+    //computations to insert at the start of each block and upward exposed ones to replace, the
+    //original computation only stays if it is the latest point and nothing else reads it
+    let plan = cfg
+        .graph
+        .node_indices()
+        .map(|node| {
+            let latest = &latest[node.index()];
+            let used = &used[node.index()];
+            let replace = e_use[node.index()]
+                .iter()
+                .filter(|e| !latest.contains(*e) || used.contains(*e))
+                .cloned()
+                .collect::<HashSet<_>>();
+            (node, latest & used, replace)
+        })
+        .collect::<Vec<_>>();
+
+    //a variable that is only ever assigned one expression can hold that expression instead of a
+    //new temporary, as long as it isn't live where the expression is inserted
+    let (live, _) = live_variable_analysis(cfg);
+    let mut holds = HashMap::<String, Option<Expr>>::new();
+    for block in cfg.graph.node_weights() {
+        for code in &block.instructions {
+            let e = expr(code).map(|(e, _, _)| e);
+            for var in code.defs() {
+                holds
+                    .entry(var)
+                    .and_modify(|x| {
+                        if *x != e {
+                            *x = None
+                        }
+                    })
+                    .or_insert(e.clone());
+            }
+        }
+    }
+    for arg in &cfg.args {
+        holds.insert(arg.name.clone(), None);
+    }
+    let mut holds = holds
+        .into_iter()
+        .filter_map(|(var, e)| Some((var, e?)))
+        .collect::<Vec<_>>();
+    holds.sort_by(|a, b| a.0.cmp(&b.0));
+    let mut temps = HashMap::new();
+    for (var, e) in holds {
+        let dead_at_inserts = plan
+            .iter()
+            .all(|(node, insert, _)| !insert.contains(&e) || !live[node.index()].contains(&var));
+        if !e.1.contains(&var) && dead_at_inserts {
+            temps.entry(e).or_insert(var);
+        }
+    }
+    let mut names = cfg
+        .graph
+        .node_weights()
+        .flat_map(|x| {
+            x.instructions
+                .iter()
+                .flat_map(|x| x.uses().into_iter().chain(x.defs()))
+        })
+        .chain(cfg.args.iter().map(|x| x.name.clone()))
+        .collect::<HashSet<_>>();
+    let mut temp = |e: &Expr| -> String {
+        temps
+            .entry(e.clone())
+            .or_insert_with(|| {
+                let mut count = 0;
+                while names.contains(&format!("_pre{}", count)) {
+                    count += 1;
+                }
+                let name = format!("_pre{}", count);
+                names.insert(name.clone());
+                name
+            })
+            .clone()
+    };
+
+    let mut changed = vec![];
+    for (node, insert, replace) in plan {
+        if insert.is_empty() && replace.is_empty() {
+            continue;
+        }
+        changed.push(node);
+
+        let mut insert = insert.into_iter().collect::<Vec<_>>();
+        insert.sort_by(|a, b| format!("{:?}", a).cmp(&format!("{:?}", b)));
+        let mut code = insert
+            .into_iter()
+            .map(|(op, args)| {
+                let op_type = types[&(op, args.clone())].clone();
+                Code::Instruction(Instruction::Value {
+                    dest: temp(&(op, args.clone())),
+                    args,
+                    funcs: vec![],
+                    labels: vec![],
+                    op,
+                    pos: None,
+                    op_type,
+                })
+            })
+            .collect::<Vec<_>>();
+
+        let block = cfg.graph.node_weight_mut(node).unwrap();
+        let mut assigned = HashSet::<String>::new();
+        for ins in std::mem::take(&mut block.instructions) {
+            let defs = ins.defs();
+            let replaced = match expr(&ins) {
+                //only computations before an argument is assigned read the temporary's value
+                Some((e, dest, op_type))
+                    if replace.contains(&e) && e.1.iter().all(|x| !assigned.contains(x)) =>
+                {
+                    let temp = temp(&e);
+                    //the computation already assigns the temporary
+                    if temp == *dest {
+                        assigned.extend(defs);
+                        continue;
+                    }
+                    Code::Instruction(Instruction::Value {
+                        args: vec![temp],
+                        dest: dest.clone(),
+                        funcs: vec![],
+                        labels: vec![],
+                        op: ValueOps::Id,
+                        pos: None,
+                        op_type: op_type.clone(),
+                    })
+                }
+                _ => ins,
+            };
+            code.push(replaced);
+            assigned.extend(defs);
+        }
+        block.instructions = code;
+        (block.uses, block.defs) = block.uses_and_defs();
+    }
+
+    let temps = temps.into_values().collect::<HashSet<_>>();
+    let (_, live_out) = live_variable_analysis(cfg);
+    for node in changed {
+        let block = cfg.graph.node_weight_mut(node).unwrap();
+        forward_copies(block, &temps, &live_out[node.index()]);
+    }
+    remove_empty_splits(cfg, &split);
+    cfg.recompute_defs();
+}
+
+/// replacing a computation with a copy of a temporary costs as much as the computation, so reads
+/// of the copy later in [block] read the temporary instead and copies that are no longer read are
+/// removed. [live_out] is the set of variables live at the end of the block
+fn forward_copies(block: &mut BasicBlock, temps: &HashSet<String>, live_out: &HashSet<String>) {
+    let mut copy_of = HashMap::<String, String>::new();
+    for code in block.instructions.iter_mut() {
+        if let Code::Instruction(
+            Instruction::Value { args, .. } | Instruction::Effect { args, .. },
+        ) = code
+        {
+            for arg in args.iter_mut() {
+                if let Some(temp) = copy_of.get(arg) {
+                    *arg = temp.clone();
+                }
+            }
+        }
+        for var in code.defs() {
+            copy_of.retain(|dest, temp| *dest != var && *temp != var);
+        }
+        if let Code::Instruction(Instruction::Value {
+            op: ValueOps::Id,
+            args,
+            dest,
+            ..
+        }) = code
+        {
+            if temps.contains(&args[0]) && args[0] != *dest {
+                copy_of.insert(dest.clone(), args[0].clone());
+            }
+        }
+    }
+
+    let mut live = live_out.clone();
+    let mut instructions = vec![];
+    for code in std::mem::take(&mut block.instructions).into_iter().rev() {
+        let dead_copy = match &code {
+            Code::Instruction(Instruction::Value {
+                op: ValueOps::Id,
+                args,
+                dest,
+                ..
+            }) => temps.contains(&args[0]) && !live.contains(dest),
+            _ => false,
+        };
+        if dead_copy {
+            continue;
+        }
+        live = &live - &code.defs();
+        live.extend(code.uses());
+        instructions.push(code);
+    }
+    instructions.reverse();
+    block.instructions = instructions;
+    (block.uses, block.defs) = block.uses_and_defs();
+}
+
+/// bypasses the blocks in [split] that only hold their jump
+fn remove_empty_splits(cfg: &mut CFG<BasicBlock>, split: &HashSet<NodeIndex>) {
+    for node in split {
+        let block = cfg.graph.node_weight(*node).unwrap();
+        if block.instructions.len() != 1 {
+            continue;
+        }
+        let label = block.label.clone().unwrap();
+        let to = cfg.graph.neighbors(*node).next().unwrap();
+        let to_label = cfg.graph.node_weight(to).unwrap().label.clone().unwrap();
+        let from = cfg
+            .graph
+            .neighbors_directed(*node, petgraph::Direction::Incoming)
+            .next()
+            .unwrap();
+
+        if let Some(Code::Instruction(Instruction::Effect {
+            labels: targets, ..
+        })) = cfg
+            .graph
+            .node_weight_mut(from)
+            .unwrap()
+            .instructions
+            .last_mut()
+        {
+            for target in targets.iter_mut() {
+                if *target == label {
+                    *target = to_label.clone();
+                }
+            }
+        }
+        while let Some(edge) = cfg.graph.find_edge(from, *node) {
+            let weight = *cfg.graph.edge_weight(edge).unwrap();
+            cfg.graph.remove_edge(edge);
+            cfg.graph.add_edge(from, to, weight);
+        }
+    }
+    //the bypassed blocks are now unreachable
+    cfg.delete_unreachable();
+}
@@ -0,0 +1,225 @@
+use std::collections::{BTreeMap, HashSet};
+use std::time::Duration;
+
+use bril_rs::{Code, ConstOps, EffectOps, Function, Instruction, Literal, Program, Type};
+use petgraph::visit::EdgeRef;
+use serde::{Deserialize, Serialize};
+
+use crate::cfg::graph_from_function;
+use crate::utils::{CFGNode, CF};
+use crate::validate::{has_phis, run};
+
+/// how often each edge of a function ran, by the labels of its source and target blocks
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct FunctionProfile {
+    pub edges: BTreeMap<String, BTreeMap<String, u64>>,
+}
+
+impl FunctionProfile {
+    pub fn count(&self, from: &str, to: &str) -> u64 {
+        self.edges
+            .get(from)
+            .and_then(|x| x.get(to))
+            .copied()
+            .unwrap_or(0)
+    }
+}
+
+/// the edge profile of a program by function name, `{"function": {"from": {"to": count}}}` in
+/// JSON. blocks are labeled as in [crate::cfg::graph_from_function], so a profile only fits
+/// the program it was collected on
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct EdgeProfile {
+    pub functions: BTreeMap<String, FunctionProfile>,
+}
+
+/// the block a marker printed by the instrumented program stands for
+struct Marker {
+    function: String,
+    label: String,
+    entry: bool,
+}
+
+/// marker printed before a function returns
+const RETURN: i64 = -1;
+
+fn print_marker(var: &str, marker: i64) -> [Code; 2] {
+    [
+        Code::Instruction(Instruction::Constant {
+            dest: var.to_string(),
+            op: ConstOps::Const,
+            pos: None,
+            const_type: Type::Int,
+            value: Literal::Int(marker),
+        }),
+        Code::Instruction(Instruction::Effect {
+            args: vec![var.to_string()],
+            funcs: vec![],
+            labels: vec![],
+            op: EffectOps::Print,
+            pos: None,
+        }),
+    ]
+}
+
+/// a variable of [func] that is used nowhere else
+fn unused_name(func: &Function) -> String {
+    let mut names = func
+        .args
+        .iter()
+        .map(|x| x.name.clone())
+        .collect::<HashSet<_>>();
+    for code in &func.instrs {
+        names.extend(code.uses().into_iter().chain(code.defs()));
+    }
+    let mut name = "_profile".to_string();
+    while names.contains(&name) {
+        name.push('_');
+    }
+    name
+}
+
+/// replaces the prints of [func] with one of a marker at the start of every block and before
+/// every return, and adds the marker of each block to [markers]
+fn instrument_function(func: &Function, markers: &mut Vec<Marker>) -> Function {
+    let mut cfg = graph_from_function(func);
+    let var = unused_name(func);
+    let start = cfg.start();
+    for node in cfg.graph.node_indices().collect::<Vec<_>>() {
+        let exit = cfg.graph.neighbors(node).next().is_none();
+        let block = cfg.graph.node_weight_mut(node).unwrap();
+        block.instructions.retain(|code| {
+            !matches!(
+                code,
+                Code::Instruction(Instruction::Effect {
+                    op: EffectOps::Print,
+                    ..
+                })
+            )
+        });
+        let at = match block.instructions.last().map(|x| x.control_flow()) {
+            Some(CF::Return) => Some(block.instructions.len() - 1),
+            _ if exit => Some(block.instructions.len()),
+            _ => None,
+        };
+        if let Some(at) = at {
+            block
+                .instructions
+                .splice(at..at, print_marker(&var, RETURN));
+        }
+        block
+            .instructions
+            .splice(0..0, print_marker(&var, markers.len() as i64));
+        markers.push(Marker {
+            function: func.name.clone(),
+            label: block.label.clone().unwrap(),
+            entry: node == start,
+        });
+        (block.uses, block.defs) = block.uses_and_defs();
+    }
+    cfg.recompute_defs();
+    Function {
+        instrs: cfg.flatten(),
+        ..func.clone()
+    }
+}
+
+/// runs [prog] in brilirs with [args] to main and counts how often each edge of every
+/// function ran. the program runs instrumented: its own prints are dropped and each block
+/// prints a marker instead, so the markers can be followed through calls. fails if the program
+/// still has phis, the run ends with an error or it doesn't finish within [budget]
+pub fn collect(prog: &Program, args: &[String], budget: Duration) -> Result<EdgeProfile, String> {
+    if has_phis(prog) {
+        return Err("can't profile a program with phis, convert it out of SSA first".to_string());
+    }
+    let mut markers = vec![];
+    let instrumented = Program {
+        functions: prog
+            .functions
+            .iter()
+            .map(|x| instrument_function(x, &mut markers))
+            .collect(),
+        imports: prog.imports.clone(),
+    };
+    let result = run(&instrumented, args, budget);
+    if let Some(e) = result.error {
+        return Err(format!("the program failed while profiling: {}", e));
+    }
+
+    let mut profile = EdgeProfile::default();
+    //the block each active call is in
+    let mut stack = vec![];
+    for line in result.output.lines() {
+        let marker = line.trim().parse::<i64>().map_err(|e| e.to_string())?;
+        if marker == RETURN {
+            stack.pop();
+            continue;
+        }
+        let to = &markers[marker as usize];
+        if !to.entry {
+            if let Some(from) = stack.pop() {
+                let from: &Marker = &markers[from];
+                *profile
+                    .functions
+                    .entry(to.function.clone())
+                    .or_default()
+                    .edges
+                    .entry(from.label.clone())
+                    .or_default()
+                    .entry(to.label.clone())
+                    .or_default() += 1;
+            }
+        }
+        stack.push(marker as usize);
+    }
+    Ok(profile)
+}
+
+/// how many times control went to a block other than the one right after it in [func], if it
+/// followed the edges of [profile]
+fn function_taken_jumps(func: &Function, profile: &FunctionProfile) -> u64 {
+    let cfg = graph_from_function(func);
+    let label = |x| cfg.graph.node_weight(x).unwrap().label.clone().unwrap();
+    cfg.graph
+        .edge_references()
+        .filter(|x| x.target().index() != x.source().index() + 1)
+        .map(|x| profile.count(&label(x.source()), &label(x.target())))
+        .sum()
+}
+
+/// the taken jumps of every function of [prog], see [function_taken_jumps]
+pub fn taken_jumps(prog: &Program, profile: &EdgeProfile) -> u64 {
+    prog.functions
+        .iter()
+        .filter_map(|x| Some(function_taken_jumps(x, profile.functions.get(&x.name)?)))
+        .sum()
+}
+
+/// lays out the blocks of every function of [prog] by [profile], see
+/// [crate::cfg::CFG::flatten_with_profile]. the greedy chains can take more jumps than the
+/// order a function already has, which is then kept
+pub fn layout(prog: Program, profile: &EdgeProfile) -> Program {
+    let empty = FunctionProfile::default();
+    Program {
+        functions: prog
+            .functions
+            .into_iter()
+            .map(|func| {
+                let profile = profile.functions.get(&func.name).unwrap_or(&empty);
+                let laid_out = Function {
+                    instrs: graph_from_function(&func).flatten_with_profile(profile),
+                    ..func.clone()
+                };
+                match function_taken_jumps(&laid_out, profile)
+                    < function_taken_jumps(&func, profile)
+                {
+                    true => laid_out,
+                    false => func,
+                }
+            })
+            .collect(),
+        imports: prog.imports,
+    }
+}
@@ -0,0 +1,694 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+
+use bril_rs::{
+    Argument, Code, ConstOps, EffectOps, Function, Instruction, Literal, Program, Type, ValueOps,
+};
+use petgraph::graph::NodeIndex;
+
+use crate::analysis::instruction_liveness;
+use crate::cfg::{graph_from_function, CFG};
+use crate::loops::find_loops;
+use crate::ssa::{from_ssa, lift_phis};
+use crate::utils::{BasicBlock, CFGNode, CF};
+
+/// register allocation by graph coloring. the variables of each function are renamed to
+/// registers `r0` to `r{k-1}`, variables that don't fit are kept in memory allocated when the
+/// function starts. returns an error naming the first function that needs more than [k]
+/// registers for the values a single instruction reads and writes
+pub fn regalloc(prog: Program, k: usize) -> Result<Program, String> {
+    Ok(Program {
+        functions: prog
+            .functions
+            .into_iter()
+            .map(|x| regalloc_function(x, k))
+            .collect::<Result<_, _>>()?,
+        imports: prog.imports,
+    })
+}
+
+fn regalloc_function(func: Function, k: usize) -> Result<Function, String> {
+    let mut cfg = graph_from_function(&func);
+    if lift_phis(&mut cfg) {
+        cfg = from_ssa(&cfg);
+    }
+    let mut spills = Spills::new(&cfg);
+    let colors = loop {
+        let graph = Interference::new(&cfg, &spills.unspillable);
+        let arrays = spills.arrays.iter().map(|x| &x.0).collect::<Vec<_>>();
+        match graph.color(&cfg, k, &arrays) {
+            Ok(colors) => break colors,
+            Err(vars) if vars.is_empty() => {
+                return Err(format!(
+                    "@{} needs more than {} registers, try a larger --registers",
+                    func.name, k
+                ))
+            }
+            Err(vars) => {
+                for var in vars {
+                    spills.spill(&mut cfg, &var);
+                }
+                spills.place_frees(&mut cfg);
+            }
+        }
+    };
+
+    let register = |var: &String| format!("r{}", colors[var]);
+    for block in cfg.graph.node_weights_mut() {
+        for code in &mut block.instructions {
+            rename(code, register);
+        }
+        //copies between variables that share a register do nothing
+        block.instructions.retain(|code| {
+            !matches!(code, Code::Instruction(Instruction::Value {
+                op: ValueOps::Id,
+                args,
+                dest,
+                ..
+            }) if args[0] == *dest)
+        });
+        (block.uses, block.defs) = block.uses_and_defs();
+    }
+    cfg.recompute_defs();
+    let args = cfg
+        .args
+        .iter()
+        .map(|x| Argument {
+            name: register(&x.name),
+            ..x.clone()
+        })
+        .collect();
+    Ok(Function {
+        args,
+        instrs: cfg.flatten(),
+        ..func
+    })
+}
+
+/// renames the arguments and destination of [code] with [name]
+fn rename(code: &mut Code, name: impl Fn(&String) -> String) {
+    match code {
+        Code::Instruction(Instruction::Constant { dest, .. }) => *dest = name(dest),
+        Code::Instruction(Instruction::Value { args, dest, .. }) => {
+            *dest = name(dest);
+            for arg in args {
+                *arg = name(arg);
+            }
+        }
+        Code::Instruction(Instruction::Effect { args, .. }) => {
+            for arg in args {
+                *arg = name(arg);
+            }
+        }
+        Code::Label { .. } => (),
+    }
+}
+
+/// the copy `dest = id src` as `(dest, src)`
+fn copy(code: &Code) -> Option<(&String, &String)> {
+    match code {
+        Code::Instruction(Instruction::Value {
+            op: ValueOps::Id,
+            args,
+            dest,
+            ..
+        }) => Some((dest, &args[0])),
+        _ => None,
+    }
+}
+
+/// type of every variable of [cfg]
+fn var_types(cfg: &CFG<BasicBlock>) -> HashMap<String, Type> {
+    let mut types = cfg
+        .args
+        .iter()
+        .map(|x| (x.name.clone(), x.arg_type.clone()))
+        .collect::<HashMap<_, _>>();
+    for code in cfg.graph.node_weights().flat_map(|x| &x.instructions) {
+        match code {
+            Code::Instruction(Instruction::Constant {
+                dest, const_type, ..
+            }) => types.insert(dest.clone(), const_type.clone()),
+            Code::Instruction(Instruction::Value { dest, op_type, .. }) => {
+                types.insert(dest.clone(), op_type.clone())
+            }
+            _ => None,
+        };
+    }
+    types
+}
+
+/// the interference graph of a function. two variables interfere when one is assigned while the
+/// other holds a value that may still be read. a copy doesn't make its destination interfere
+/// with its source
+struct Interference {
+    adjacent: BTreeMap<String, BTreeSet<String>>,
+    /// pairs of variables related by a copy, the candidates for coalescing
+    moves: Vec<(String, String)>,
+    /// variables merged into each node by coalescing, including the node itself
+    members: BTreeMap<String, Vec<String>>,
+    unspillable: HashSet<String>,
+    types: HashMap<String, Type>,
+}
+
+impl Interference {
+    fn new(cfg: &CFG<BasicBlock>, unspillable: &HashSet<String>) -> Self {
+        let mut graph = Interference {
+            adjacent: BTreeMap::new(),
+            moves: vec![],
+            members: BTreeMap::new(),
+            unspillable: unspillable.clone(),
+            types: var_types(cfg),
+        };
+        let live = instruction_liveness(cfg);
+        for arg in &cfg.args {
+            graph.add_node(&arg.name);
+        }
+        for node in cfg.graph.node_indices() {
+            let block = cfg.graph.node_weight(node).unwrap();
+            for (i, code) in block.instructions.iter().enumerate() {
+                for var in code.uses().iter().chain(&code.defs()) {
+                    graph.add_node(var);
+                }
+                let source = copy(code).map(|(_, src)| src);
+                for dest in code.defs() {
+                    for var in &live[node.index()][i + 1] {
+                        if *var != dest && Some(var) != source {
+                            graph.add_edge(&dest, var);
+                        }
+                    }
+                }
+                if let Some((dest, src)) = copy(code) {
+                    graph.moves.push((dest.clone(), src.clone()));
+                }
+            }
+        }
+        //the arguments are all assigned when the function starts
+        let start = &live[cfg.start().index()][0];
+        for (i, arg) in cfg.args.iter().enumerate() {
+            for other in cfg.args[i + 1..].iter().map(|x| &x.name).chain(start) {
+                if *other != arg.name {
+                    graph.add_edge(&arg.name, other);
+                }
+            }
+        }
+        graph
+    }
+
+    fn add_node(&mut self, var: &String) {
+        if !self.adjacent.contains_key(var) {
+            self.adjacent.insert(var.clone(), BTreeSet::new());
+            self.members.insert(var.clone(), vec![var.clone()]);
+        }
+    }
+
+    fn add_edge(&mut self, a: &String, b: &String) {
+        self.add_node(a);
+        self.add_node(b);
+        self.adjacent.get_mut(a).unwrap().insert(b.clone());
+        self.adjacent.get_mut(b).unwrap().insert(a.clone());
+    }
+
+    fn degree(&self, var: &String) -> usize {
+        self.adjacent[var].len()
+    }
+
+    /// merges the variables of copies that don't interfere when the Briggs test allows it: the
+    /// merged node has fewer than [k] neighbors of significant degree, so it can't make the
+    /// graph harder to color. variables added by spilling are never merged
+    fn coalesce(&mut self, k: usize) {
+        let mut alias = HashMap::<String, String>::new();
+        let find = |alias: &HashMap<String, String>, mut var: String| {
+            while let Some(next) = alias.get(&var) {
+                var = next.clone();
+            }
+            var
+        };
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for (a, b) in self.moves.clone() {
+                let (a, b) = (find(&alias, a), find(&alias, b));
+                if a == b
+                    || self.adjacent[&a].contains(&b)
+                    || self.unspillable.contains(&a)
+                    || self.unspillable.contains(&b)
+                {
+                    continue;
+                }
+                let neighbors = self.adjacent[&a].union(&self.adjacent[&b]).cloned();
+                let significant = neighbors.filter(|x| self.degree(x) >= k).count();
+                if significant >= k {
+                    continue;
+                }
+                for var in self.adjacent.remove(&b).unwrap() {
+                    let adjacent = self.adjacent.get_mut(&var).unwrap();
+                    adjacent.remove(&b);
+                    adjacent.insert(a.clone());
+                    self.adjacent.get_mut(&a).unwrap().insert(var);
+                }
+                let merged = self.members.remove(&b).unwrap();
+                self.members.get_mut(&a).unwrap().extend(merged);
+                alias.insert(b, a);
+                changed = true;
+            }
+        }
+    }
+
+    /// colors the graph with [k] colors after coalescing. nodes of degree less than [k] are
+    /// removed first; when there are none, the spillable node with the lowest spill cost per
+    /// neighbor is removed, optimistically hoping it still gets a color. returns the color of
+    /// every variable, or the variables to spill, which is empty if nothing can be spilled
+    fn color(
+        mut self,
+        cfg: &CFG<BasicBlock>,
+        k: usize,
+        arrays: &[&Type],
+    ) -> Result<HashMap<String, usize>, Vec<String>> {
+        self.coalesce(k);
+        let cost = spill_costs(cfg, &self.types, arrays);
+        let node_cost = |var: &String| -> f64 {
+            self.members[var]
+                .iter()
+                .map(|x| cost.get(x).copied().unwrap_or(0.0))
+                .sum()
+        };
+
+        let mut degree = self
+            .adjacent
+            .iter()
+            .map(|(var, adjacent)| (var.clone(), adjacent.len()))
+            .collect::<BTreeMap<_, _>>();
+        let mut stack = vec![];
+        while !degree.is_empty() {
+            let var = match degree.iter().find(|(_, degree)| **degree < k) {
+                Some((var, _)) => var.clone(),
+                None => degree
+                    .iter()
+                    .min_by(|(a, x), (b, y)| {
+                        let spillable = |var: &String| !self.unspillable.contains(var);
+                        spillable(b).cmp(&spillable(a)).then(
+                            (node_cost(a) / **x as f64).total_cmp(&(node_cost(b) / **y as f64)),
+                        )
+                    })
+                    .map(|(var, _)| var.clone())
+                    .unwrap(),
+            };
+            degree.remove(&var);
+            for neighbor in &self.adjacent[&var] {
+                if let Some(degree) = degree.get_mut(neighbor) {
+                    *degree -= 1;
+                }
+            }
+            stack.push(var);
+        }
+
+        //a register holds values of a single type, it gets the type of the first variable in it.
+        //a register of the same type is preferred, and an unused one is only taken while enough
+        //are left for the types on the stack that have no register yet
+        let mut register_types = HashMap::<usize, &Type>::new();
+        let mut colors = HashMap::<String, usize>::new();
+        let mut uncolored = vec![];
+        while let Some(var) = stack.pop() {
+            let taken = self.adjacent[&var]
+                .iter()
+                .filter_map(|x| colors.get(x))
+                .collect::<HashSet<_>>();
+            let var_type = self.types.get(&var);
+            let waiting = stack
+                .iter()
+                .filter_map(|x| self.types.get(x))
+                .filter(|x| Some(*x) != var_type && !register_types.values().any(|y| y == x))
+                .collect::<HashSet<_>>()
+                .len();
+            let unused = k - register_types.len();
+            let free = (0..k).filter(|x| !taken.contains(x));
+            let color = match var_type {
+                Some(var_type) => free
+                    .filter(|x| match register_types.get(x) {
+                        Some(x) => *x == var_type,
+                        None => unused > waiting,
+                    })
+                    .min_by_key(|x| !register_types.contains_key(x)),
+                None => free.min(),
+            };
+            match color {
+                Some(color) => {
+                    if let Some(var_type) = var_type {
+                        register_types.insert(color, var_type);
+                    }
+                    colors.insert(var, color);
+                }
+                None => uncolored.push(var),
+            }
+        }
+
+        //a variable added by spilling can't be spilled again, its cheapest spillable neighbor
+        //is spilled instead to make room for it
+        let mut spills = BTreeSet::new();
+        for var in &uncolored {
+            let spill = match self.unspillable.contains(var) {
+                true => self.adjacent[var]
+                    .iter()
+                    .filter(|x| !self.unspillable.contains(*x))
+                    .min_by(|a, b| node_cost(a).total_cmp(&node_cost(b))),
+                false => Some(var),
+            };
+            spills.extend(
+                spill
+                    .into_iter()
+                    .flat_map(|x| self.members[x].iter().cloned()),
+            );
+        }
+        if !uncolored.is_empty() {
+            return Err(spills.into_iter().collect());
+        }
+        Ok(self
+            .members
+            .iter()
+            .flat_map(|(var, members)| members.iter().map(|x| (x.clone(), colors[var])))
+            .collect())
+    }
+}
+
+/// variables whose every definition is the same `const`, which are spilled by redefining them
+/// before each use instead of keeping them in memory
+fn constants(cfg: &CFG<BasicBlock>) -> HashMap<String, Code> {
+    let mut constants = HashMap::<String, Option<Code>>::new();
+    for arg in &cfg.args {
+        constants.insert(arg.name.clone(), None);
+    }
+    for code in cfg.graph.node_weights().flat_map(|x| &x.instructions) {
+        for var in code.defs() {
+            let def = match code {
+                Code::Instruction(Instruction::Constant { .. }) => Some(code.clone()),
+                _ => None,
+            };
+            let entry = constants.entry(var).or_insert_with(|| def.clone());
+            if *entry != def {
+                *entry = None;
+            }
+        }
+    }
+    constants
+        .into_iter()
+        .filter_map(|(var, def)| Some((var, def?)))
+        .collect()
+}
+
+/// the cost of keeping each variable in memory: each read and write, weighted by 10 for every
+/// loop it is in. writes of [constants] are free, they are dropped when spilled. the first
+/// variable of a type that is spilled needs an array, which takes a register for the whole
+/// function, so variables of types that already have one go first
+fn spill_costs(
+    cfg: &CFG<BasicBlock>,
+    types: &HashMap<String, Type>,
+    arrays: &[&Type],
+) -> HashMap<String, f64> {
+    let forest = find_loops(cfg);
+    let constants = constants(cfg);
+    let mut cost = HashMap::<String, f64>::new();
+    for node in cfg.graph.node_indices() {
+        let weight = 10f64.powi(forest.depth(node).min(8) as i32);
+        for code in &cfg.graph.node_weight(node).unwrap().instructions {
+            for var in code.uses() {
+                *cost.entry(var).or_default() += weight;
+            }
+            for var in code.defs() {
+                *cost.entry(var.clone()).or_default() += if constants.contains_key(&var) {
+                    0.0
+                } else {
+                    weight
+                };
+            }
+        }
+    }
+    for (var, cost) in cost.iter_mut() {
+        if !constants.contains_key(var) && types.get(var).is_some_and(|x| !arrays.contains(&x)) {
+            *cost += 1e12;
+        }
+    }
+    cost
+}
+
+/// memory for the variables that didn't get a register, an array per type allocated at the
+/// start of the function and freed before it returns
+struct Spills {
+    /// type, array, variable holding its size and number of slots of each array
+    arrays: Vec<(Type, String, String, i64)>,
+    /// array and index of each spilled variable
+    slots: HashMap<String, (String, i64)>,
+    types: HashMap<String, Type>,
+    /// variables added by spilling, which live too briefly to spill again
+    unspillable: HashSet<String>,
+    names: HashSet<String>,
+    count: usize,
+}
+
+fn constant(dest: String, value: i64) -> Code {
+    Code::Instruction(Instruction::Constant {
+        dest,
+        op: ConstOps::Const,
+        pos: None,
+        const_type: Type::Int,
+        value: Literal::Int(value),
+    })
+}
+
+fn value(dest: String, op: ValueOps, args: Vec<String>, op_type: Type) -> Code {
+    Code::Instruction(Instruction::Value {
+        args,
+        dest,
+        funcs: vec![],
+        labels: vec![],
+        op,
+        pos: None,
+        op_type,
+    })
+}
+
+fn effect(op: EffectOps, args: Vec<String>) -> Code {
+    Code::Instruction(Instruction::Effect {
+        args,
+        funcs: vec![],
+        labels: vec![],
+        op,
+        pos: None,
+    })
+}
+
+impl Spills {
+    fn new(cfg: &CFG<BasicBlock>) -> Self {
+        let mut names = cfg
+            .args
+            .iter()
+            .map(|x| x.name.clone())
+            .collect::<HashSet<_>>();
+        for code in cfg.graph.node_weights().flat_map(|x| &x.instructions) {
+            names.extend(code.uses().into_iter().chain(code.defs()));
+        }
+        Spills {
+            arrays: vec![],
+            slots: HashMap::new(),
+            types: var_types(cfg),
+            unspillable: HashSet::new(),
+            names,
+            count: 0,
+        }
+    }
+
+    /// a new variable that can't be spilled
+    fn fresh(&mut self) -> String {
+        while self.names.contains(&format!("_ra{}", self.count)) {
+            self.count += 1;
+        }
+        let name = format!("_ra{}", self.count);
+        self.names.insert(name.clone());
+        self.unspillable.insert(name.clone());
+        name
+    }
+
+    /// the code computing the address of the slot of [var], and the variable holding it
+    fn address(&mut self, var: &String) -> (Vec<Code>, String) {
+        let (array, index) = self.slots[var].clone();
+        if index == 0 {
+            return (vec![], array);
+        }
+        let (i, address) = (self.fresh(), self.fresh());
+        let ptr = Type::Pointer(Box::new(self.types[var].clone()));
+        (
+            vec![
+                constant(i.clone(), index),
+                value(address.clone(), ValueOps::PtrAdd, vec![array, i], ptr),
+            ],
+            address,
+        )
+    }
+
+    /// gives [var] a slot, allocating the array for its type at the start of [cfg] if it is
+    /// the first of that type
+    fn assign_slot(&mut self, cfg: &mut CFG<BasicBlock>, var: &String) {
+        let var_type = self.types[var].clone();
+        let existing = self.arrays.iter().position(|x| x.0 == var_type);
+        let i = match existing {
+            Some(i) => i,
+            None => {
+                let (array, size) = (self.fresh(), self.fresh());
+                let ptr = Type::Pointer(Box::new(var_type.clone()));
+                let entry = cfg.graph.node_weight_mut(cfg.start()).unwrap();
+                entry.instructions.splice(
+                    0..0,
+                    [
+                        constant(size.clone(), 0),
+                        value(array.clone(), ValueOps::Alloc, vec![size.clone()], ptr),
+                    ],
+                );
+                self.arrays.push((var_type, array, size, 0));
+                self.arrays.len() - 1
+            }
+        };
+        let (_, array, size, slots) = &mut self.arrays[i];
+        self.slots.insert(var.clone(), (array.clone(), *slots));
+        *slots += 1;
+        //the size of the array is the constant the entry block starts with
+        let slots = *slots;
+        let size = size.clone();
+        for code in &mut cfg.graph.node_weight_mut(cfg.start()).unwrap().instructions {
+            if let Code::Instruction(Instruction::Constant { dest, value, .. }) = code {
+                if *dest == size {
+                    *value = Literal::Int(slots);
+                }
+            }
+        }
+    }
+
+    /// keeps [var] in memory: every read loads it into a new variable first, every write goes
+    /// to a new variable that is stored afterwards. an argument is stored when the function
+    /// starts
+    fn spill(&mut self, cfg: &mut CFG<BasicBlock>, var: &String) {
+        if let Some(constant) = constants(cfg).remove(var) {
+            return self.rematerialize(cfg, var, &constant);
+        }
+        self.assign_slot(cfg, var);
+        for node in cfg.graph.node_indices().collect::<Vec<_>>() {
+            let instructions =
+                std::mem::take(&mut cfg.graph.node_weight_mut(node).unwrap().instructions);
+            let mut out = vec![];
+            for mut code in instructions {
+                if code.uses().contains(var) {
+                    let (mut load, address) = self.address(var);
+                    let temp = self.fresh();
+                    load.push(value(
+                        temp.clone(),
+                        ValueOps::Load,
+                        vec![address],
+                        self.types[var].clone(),
+                    ));
+                    out.extend(load);
+                    rename_args(&mut code, var, &temp);
+                }
+                if code.defs().contains(var) {
+                    let temp = self.fresh();
+                    rename(
+                        &mut code,
+                        |x| if x == var { temp.clone() } else { x.clone() },
+                    );
+                    out.push(code);
+                    let (store, address) = self.address(var);
+                    out.extend(store);
+                    out.push(effect(EffectOps::Store, vec![address, temp]));
+                } else {
+                    out.push(code);
+                }
+            }
+            cfg.graph.node_weight_mut(node).unwrap().instructions = out;
+        }
+
+        if let Some(i) = cfg.args.iter().position(|x| x.name == *var) {
+            let temp = self.fresh();
+            cfg.args[i].name = temp.clone();
+            let (mut store, address) = self.address(var);
+            store.push(effect(EffectOps::Store, vec![address, temp]));
+            //after the allocations at the start of the entry block
+            let entry = cfg.graph.node_weight_mut(cfg.start()).unwrap();
+            let at = 2 * self.arrays.len();
+            entry.instructions.splice(at..at, store);
+        }
+        for block in cfg.graph.node_weights_mut() {
+            (block.uses, block.defs) = block.uses_and_defs();
+        }
+        cfg.recompute_defs();
+    }
+
+    /// replaces the constant [var] with a copy of its definition [constant] before each use
+    fn rematerialize(&mut self, cfg: &mut CFG<BasicBlock>, var: &String, constant: &Code) {
+        for block in cfg.graph.node_weights_mut() {
+            let instructions = std::mem::take(&mut block.instructions);
+            for mut code in instructions {
+                if code.defs().contains(var) {
+                    continue;
+                }
+                if code.uses().contains(var) {
+                    let temp = self.fresh();
+                    let mut def = constant.clone();
+                    rename(&mut def, |_| temp.clone());
+                    block.instructions.push(def);
+                    rename_args(&mut code, var, &temp);
+                }
+                block.instructions.push(code);
+            }
+            (block.uses, block.defs) = block.uses_and_defs();
+        }
+        cfg.recompute_defs();
+    }
+
+    /// frees every array right before each `ret` and at the end of blocks that leave the
+    /// function without one
+    fn place_frees(&self, cfg: &mut CFG<BasicBlock>) {
+        let arrays = self
+            .arrays
+            .iter()
+            .map(|x| x.1.clone())
+            .collect::<HashSet<_>>();
+        let exits = cfg
+            .graph
+            .node_indices()
+            .filter(|x| cfg.graph.neighbors(*x).next().is_none())
+            .collect::<HashSet<NodeIndex>>();
+        for node in cfg.graph.node_indices().collect::<Vec<_>>() {
+            let block = cfg.graph.node_weight_mut(node).unwrap();
+            block.instructions.retain(|code| {
+                !matches!(code, Code::Instruction(Instruction::Effect {
+                    op: EffectOps::Free,
+                    args,
+                    ..
+                }) if arrays.contains(&args[0]))
+            });
+            let frees = self
+                .arrays
+                .iter()
+                .map(|x| effect(EffectOps::Free, vec![x.1.clone()]));
+            match block.instructions.last().map(|x| x.control_flow()) {
+                Some(CF::Return) => {
+                    let at = block.instructions.len() - 1;
+                    block.instructions.splice(at..at, frees);
+                }
+                _ if exits.contains(&node) => block.instructions.extend(frees),
+                _ => (),
+            }
+            (block.uses, block.defs) = block.uses_and_defs();
+        }
+    }
+}
+
+/// renames the reads of [var] in [code] to [temp]
+fn rename_args(code: &mut Code, var: &String, temp: &str) {
+    if let Code::Instruction(Instruction::Value { args, .. } | Instruction::Effect { args, .. }) =
+        code
+    {
+        for arg in args {
+            if arg == var {
+                *arg = temp.to_string();
+            }
+        }
+    }
+}
@@ -0,0 +1,162 @@
+use std::collections::{BTreeMap, HashSet};
+
+use bril_rs::Function;
+use petgraph::graph::NodeIndex;
+use petgraph::visit::EdgeRef;
+use petgraph::Direction;
+use serde::Serialize;
+
+use crate::analysis::live_variable_analysis;
+use crate::cfg::{graph_from_function, CFG};
+use crate::dominator::dominator_analyis;
+use crate::utils::{BasicBlock, CFGNode, CF};
+
+/// version of the document [function_report] produces, raised whenever a field changes meaning
+/// or goes away
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// the analyses of one function, printed by `--format json`. blocks are named by their label
+/// and listed in the order they appear in the CFG, the first is the entry. every set is sorted
+#[derive(Debug, Clone, Serialize)]
+pub struct FunctionReport {
+    pub version: u32,
+    pub function: String,
+    pub blocks: Vec<String>,
+    pub edges: Vec<Edge>,
+    pub liveness: BTreeMap<String, Liveness>,
+    pub dominators: BTreeMap<String, Dominance>,
+    pub dom_tree: DomTree,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Edge {
+    pub from: String,
+    pub to: String,
+    pub kind: EdgeKind,
+}
+
+/// how control reaches the target of an [Edge]: the true or false side of a `br`, or a `jmp`,
+/// which includes falling through to the next block
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EdgeKind {
+    True,
+    False,
+    Jump,
+}
+
+/// variables live at the start (`in`) and end (`out`) of a block
+#[derive(Debug, Clone, Serialize)]
+pub struct Liveness {
+    #[serde(rename = "in")]
+    pub live_in: Vec<String>,
+    #[serde(rename = "out")]
+    pub live_out: Vec<String>,
+}
+
+/// dominance facts of a block. `dominators` goes from the block itself up to the entry and is
+/// empty for a block the entry can't reach, which has no `idom` either
+#[derive(Debug, Clone, Serialize)]
+pub struct Dominance {
+    pub dominators: Vec<String>,
+    pub idom: Option<String>,
+    pub frontier: Vec<String>,
+}
+
+/// the dominator tree, rooted at the entry. `children` has an entry for every block, leaves
+/// included
+#[derive(Debug, Clone, Serialize)]
+pub struct DomTree {
+    pub root: String,
+    pub children: BTreeMap<String, Vec<String>>,
+}
+
+fn sorted<'a>(x: impl IntoIterator<Item = &'a String>) -> Vec<String> {
+    let mut x = x.into_iter().cloned().collect::<Vec<_>>();
+    x.sort();
+    x
+}
+
+/// the CFG, liveness and dominance analyses of [func] as one document
+pub fn function_report(func: &Function) -> FunctionReport {
+    let mut cfg = graph_from_function(func);
+    cfg.label();
+    let label = |x: NodeIndex| cfg.graph.node_weight(x).unwrap().label.clone().unwrap();
+
+    let (entry, exit) = live_variable_analysis(&cfg);
+    let (dominators, frontier) = dominator_analyis(&cfg);
+
+    let mut report = FunctionReport {
+        version: SCHEMA_VERSION,
+        function: func.name.clone(),
+        blocks: cfg.graph.node_indices().map(label).collect(),
+        edges: edges(&cfg),
+        liveness: BTreeMap::new(),
+        dominators: BTreeMap::new(),
+        dom_tree: DomTree {
+            root: label(cfg.start()),
+            children: BTreeMap::new(),
+        },
+    };
+    for node in cfg.graph.node_indices() {
+        report.liveness.insert(
+            label(node),
+            Liveness {
+                live_in: sorted(&entry[node.index()]),
+                live_out: sorted(&exit[node.index()]),
+            },
+        );
+        let frontier = frontier[node.index()]
+            .iter()
+            .map(|x| label(*x))
+            .collect::<HashSet<_>>();
+        report.dominators.insert(
+            label(node),
+            Dominance {
+                dominators: dominators
+                    .dominators(node)
+                    .map_or(vec![], |x| x.map(label).collect()),
+                idom: dominators.immediate_dominator(node).map(label),
+                frontier: sorted(&frontier),
+            },
+        );
+        let children = dominators
+            .immediately_dominated_by(node)
+            .filter(|x| *x != node)
+            .map(label)
+            .collect::<Vec<_>>();
+        report
+            .dom_tree
+            .children
+            .insert(label(node), sorted(&children));
+    }
+    report
+}
+
+fn edges(cfg: &CFG<BasicBlock>) -> Vec<Edge> {
+    let label = |x: NodeIndex| cfg.graph.node_weight(x).unwrap().label.clone().unwrap();
+    let mut edges = vec![];
+    for node in cfg.graph.node_indices() {
+        let branch = matches!(
+            cfg.graph.node_weight(node).unwrap().control_flow(),
+            CF::Branch(..)
+        );
+        let mut out = cfg
+            .graph
+            .edges_directed(node, Direction::Outgoing)
+            .map(|x| Edge {
+                from: label(node),
+                to: label(x.target()),
+                kind: match (branch, *x.weight()) {
+                    (true, true) => EdgeKind::True,
+                    (true, false) => EdgeKind::False,
+                    (false, _) => EdgeKind::Jump,
+                },
+            })
+            .collect::<Vec<_>>();
+        //petgraph lists the edges of a node newest first
+        out.reverse();
+        edges.extend(out);
+    }
+    edges
+}
@@ -0,0 +1,351 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use bril_rs::{Code, ConstOps, EffectOps, Instruction, Literal, Type, ValueOps};
+use petgraph::{graph::NodeIndex, visit::EdgeRef, Direction};
+
+use crate::cfg::CFG;
+use crate::fold::{const_value, eval, same_literal};
+use crate::ssa::remove_dead_phis;
+use crate::utils::{BasicBlock, CFGNode, CF};
+
+/// lattice value of an SSA name
+#[derive(Clone, Debug)]
+enum LatticeVal {
+    /// no executed definition seen yet
+    Top,
+    Const(Literal),
+    /// may hold more than one value at runtime
+    Bottom,
+}
+
+impl LatticeVal {
+    fn meet(&self, other: &Self) -> Self {
+        match (self, other) {
+            (LatticeVal::Top, x) | (x, LatticeVal::Top) => x.clone(),
+            (LatticeVal::Const(a), LatticeVal::Const(b)) if same_literal(a, b) => self.clone(),
+            _ => LatticeVal::Bottom,
+        }
+    }
+
+    fn same(&self, other: &Self) -> bool {
+        match (self, other) {
+            (LatticeVal::Top, LatticeVal::Top) | (LatticeVal::Bottom, LatticeVal::Bottom) => true,
+            (LatticeVal::Const(a), LatticeVal::Const(b)) => same_literal(a, b),
+            _ => false,
+        }
+    }
+}
+
+/// sparse conditional constant propagation over a CFG in SSA form (see [crate::ssa::to_ssa]).
+/// branches on constants are folded, blocks that can never execute are removed and every
+/// definition (including phis) with a constant value is replaced by a `const`
+pub fn sccp(cfg: &CFG<BasicBlock>) -> CFG<BasicBlock> {
+    let mut analysis = SCCP::new(cfg);
+    analysis.run();
+    let SCCP {
+        values,
+        exec_blocks,
+        ..
+    } = analysis;
+
+    let mut out = cfg.clone();
+    remove_dead_phis(&mut out);
+
+    //phis with a constant value become constants in the entry block, which runs only once
+    let mut consts = vec![];
+    for node in out.graph.node_indices() {
+        if !exec_blocks.contains(&node) {
+            continue;
+        }
+        let block = out.graph.node_weight_mut(node).unwrap();
+        let vartype = block.vartype.clone();
+        block
+            .phi
+            .retain(|canonical, (phi_dest, _)| match values.get(phi_dest) {
+                Some(LatticeVal::Const(l)) => {
+                    let const_type = vartype.get(canonical).unwrap().clone();
+                    consts.push(constant(phi_dest.clone(), const_type, l.clone()));
+                    false
+                }
+                _ => true,
+            });
+    }
+    consts.sort_by_key(|x| x.defs().into_iter().next());
+    let start = out.start();
+    out.graph
+        .node_weight_mut(start)
+        .unwrap()
+        .instructions
+        .splice(0..0, consts);
+
+    for node in out.graph.node_indices() {
+        if !exec_blocks.contains(&node) {
+            continue;
+        }
+        let block = out.graph.node_weight_mut(node).unwrap();
+        for code in block.instructions.iter_mut() {
+            if let Code::Instruction(Instruction::Value { dest, op_type, .. }) = code {
+                if let Some(LatticeVal::Const(l)) = values.get(dest) {
+                    *code = constant(dest.clone(), op_type.clone(), l.clone());
+                }
+            }
+        }
+
+        //branches on a constant become jumps
+        if let CF::Branch(_, _) = block.control_flow() {
+            if let Some(LatticeVal::Const(Literal::Bool(b))) =
+                branch_cond(block).and_then(|cond| values.get(&cond))
+            {
+                let b = *b;
+                fold_branch(&mut out, node, b);
+            }
+        }
+    }
+
+    out.delete_unreachable();
+
+    //drop phi sources coming from blocks that no longer exist
+    for node in out.graph.node_indices() {
+        let preds = out
+            .graph
+            .neighbors_directed(node, Direction::Incoming)
+            .map(|x| out.graph.node_weight(x).unwrap().label.clone().unwrap())
+            .collect::<HashSet<_>>();
+        let block = out.graph.node_weight_mut(node).unwrap();
+        for (_, phi_source) in block.phi.values_mut() {
+            phi_source.retain(|(_, label)| preds.contains(label));
+        }
+        (block.uses, block.defs) = block.uses_and_defs();
+    }
+    out.recompute_defs();
+    out
+}
+
+fn constant(dest: String, const_type: Type, value: Literal) -> Code {
+    Code::Instruction(Instruction::Constant {
+        dest,
+        op: ConstOps::Const,
+        pos: None,
+        const_type,
+        value,
+    })
+}
+
+/// condition variable of the `br` ending [block]
+fn branch_cond(block: &BasicBlock) -> Option<String> {
+    match block.instructions.last() {
+        Some(Code::Instruction(Instruction::Effect {
+            op: EffectOps::Branch,
+            args,
+            ..
+        })) => args.first().cloned(),
+        _ => None,
+    }
+}
+
+/// replaces the `br` ending [node] with a jump along its [taken] edge
+fn fold_branch(cfg: &mut CFG<BasicBlock>, node: NodeIndex, taken: bool) {
+    let target = cfg
+        .graph
+        .edges(node)
+        .find(|e| *e.weight() == taken)
+        .map(|e| e.target())
+        .unwrap();
+    let label = cfg
+        .graph
+        .node_weight(target)
+        .unwrap()
+        .label
+        .clone()
+        .unwrap();
+
+    let block = cfg.graph.node_weight_mut(node).unwrap();
+    block.instructions.pop();
+    block
+        .instructions
+        .push(Code::Instruction(Instruction::Effect {
+            args: vec![],
+            funcs: vec![],
+            labels: vec![label],
+            op: EffectOps::Jump,
+            pos: None,
+        }));
+
+    //flatten treats true edges as taken branches, a jump must leave through a false edge
+    while let Some(edge) = cfg.graph.edges(node).next().map(|e| e.id()) {
+        cfg.graph.remove_edge(edge);
+    }
+    cfg.graph.add_edge(node, target, false);
+}
+
+struct SCCP<'a> {
+    cfg: &'a CFG<BasicBlock>,
+    values: HashMap<String, LatticeVal>,
+    exec_edges: HashSet<(NodeIndex, NodeIndex)>,
+    exec_blocks: HashSet<NodeIndex>,
+    //blocks reading each variable, in a phi or an instruction
+    users: HashMap<String, HashSet<NodeIndex>>,
+    label_node: HashMap<String, NodeIndex>,
+    work_list: VecDeque<NodeIndex>,
+    work_list_set: HashSet<NodeIndex>,
+}
+
+impl<'a> SCCP<'a> {
+    fn new(cfg: &'a CFG<BasicBlock>) -> Self {
+        let mut users = HashMap::<String, HashSet<NodeIndex>>::new();
+        let mut label_node = HashMap::new();
+        for node in cfg.graph.node_indices() {
+            let block = cfg.graph.node_weight(node).unwrap();
+            if let Some(label) = &block.label {
+                label_node.insert(label.clone(), node);
+            }
+            let phi_uses = block
+                .phi
+                .values()
+                .map(|(_, phi_source)| phi_source.iter().map(|(var, _)| var.clone()))
+                .flatten();
+            let ins_uses = block.instructions.iter().map(|x| x.uses()).flatten();
+            for var in phi_uses.chain(ins_uses) {
+                users.entry(var).or_default().insert(node);
+            }
+        }
+
+        //arguments are unknown on entry
+        let values = cfg
+            .args
+            .iter()
+            .map(|x| (x.name.clone(), LatticeVal::Bottom))
+            .collect();
+
+        SCCP {
+            cfg,
+            values,
+            exec_edges: HashSet::new(),
+            exec_blocks: HashSet::new(),
+            users,
+            label_node,
+            work_list: VecDeque::new(),
+            work_list_set: HashSet::new(),
+        }
+    }
+
+    fn run(&mut self) {
+        let start = self.cfg.start();
+        self.exec_blocks.insert(start);
+        self.push(start);
+        while let Some(node) = self.work_list.pop_front() {
+            self.work_list_set.remove(&node);
+            self.visit(node);
+        }
+    }
+
+    fn push(&mut self, node: NodeIndex) {
+        if self.work_list_set.insert(node) {
+            self.work_list.push_back(node);
+        }
+    }
+
+    fn value(&self, var: &String) -> LatticeVal {
+        self.values.get(var).cloned().unwrap_or(LatticeVal::Top)
+    }
+
+    ///lowers the value of [var] by [val], returns true if it changed
+    fn update(&mut self, var: &String, val: LatticeVal) -> bool {
+        let old = self.value(var);
+        let new = old.meet(&val);
+        if new.same(&old) {
+            false
+        } else {
+            self.values.insert(var.clone(), new);
+            true
+        }
+    }
+
+    fn evaluate(&self, op: &ValueOps, args: &Vec<String>) -> LatticeVal {
+        let vals = args.iter().map(|x| self.value(x)).collect::<Vec<_>>();
+        if vals.iter().any(|x| matches!(x, LatticeVal::Bottom)) {
+            return LatticeVal::Bottom;
+        }
+        if vals.iter().any(|x| matches!(x, LatticeVal::Top)) {
+            return LatticeVal::Top;
+        }
+        let consts = vals
+            .into_iter()
+            .filter_map(|x| match x {
+                LatticeVal::Const(l) => Some(l),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        match eval(op, &consts) {
+            Some(l) => LatticeVal::Const(l),
+            None => LatticeVal::Bottom,
+        }
+    }
+
+    fn visit(&mut self, node: NodeIndex) {
+        let cfg = self.cfg;
+        let block = cfg.graph.node_weight(node).unwrap();
+        let mut changed = vec![];
+
+        for (phi_dest, phi_source) in block.phi.values() {
+            let val = phi_source
+                .iter()
+                .filter(|(_, label)| {
+                    self.label_node
+                        .get(label)
+                        .is_some_and(|pred| self.exec_edges.contains(&(*pred, node)))
+                })
+                .fold(LatticeVal::Top, |acc, (var, _)| acc.meet(&self.value(var)));
+            if self.update(phi_dest, val) {
+                changed.push(phi_dest);
+            }
+        }
+
+        for code in &block.instructions {
+            let (dest, val) = match code {
+                Code::Instruction(Instruction::Constant {
+                    dest,
+                    const_type,
+                    value,
+                    ..
+                }) => (dest, LatticeVal::Const(const_value(const_type, value))),
+                Code::Instruction(Instruction::Value { dest, op, args, .. }) => {
+                    (dest, self.evaluate(op, args))
+                }
+                _ => continue,
+            };
+            if self.update(dest, val) {
+                changed.push(dest);
+            }
+        }
+
+        for var in changed {
+            for user in self.users.get(var).cloned().unwrap_or_default() {
+                if self.exec_blocks.contains(&user) {
+                    self.push(user);
+                }
+            }
+        }
+
+        let successors = match block.control_flow() {
+            CF::Branch(_, _) => match branch_cond(block).map(|cond| self.value(&cond)) {
+                Some(LatticeVal::Const(Literal::Bool(b))) => cfg
+                    .graph
+                    .edges(node)
+                    .filter(|e| *e.weight() == b)
+                    .map(|e| e.target())
+                    .collect(),
+                Some(LatticeVal::Top) => vec![],
+                _ => cfg.graph.neighbors(node).collect::<Vec<_>>(),
+            },
+            _ => cfg.graph.neighbors(node).collect(),
+        };
+        for succ in successors {
+            //a newly executable edge can change the phis of [succ] even if it already ran
+            if self.exec_edges.insert((node, succ)) {
+                self.exec_blocks.insert(succ);
+                self.push(succ);
+            }
+        }
+    }
+}
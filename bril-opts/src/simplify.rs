@@ -0,0 +1,263 @@
+use std::collections::HashSet;
+
+use bril_rs::{Code, EffectOps, Function, Instruction, Program, ValueOps};
+use petgraph::graph::NodeIndex;
+use petgraph::visit::EdgeRef;
+use petgraph::Direction;
+
+use crate::cfg::{graph_from_function, CFG};
+use crate::ssa::lift_phis;
+use crate::utils::{BasicBlock, CFGNode, CF};
+
+/// CFG simplification, merges straight-line blocks, threads jumps through empty blocks and
+/// drops the labels and jumps that flattening leaves behind
+pub fn simplify(prog: Program) -> Program {
+    Program {
+        functions: prog.functions.into_iter().map(simplify_function).collect(),
+        imports: prog.imports,
+    }
+}
+
+fn simplify_function(func: Function) -> Function {
+    let mut cfg = graph_from_function(&func);
+    lift_phis(&mut cfg);
+    simplify_cfg(&mut cfg);
+    let mut instrs = cfg.flatten();
+    drop_unused_labels(&mut instrs);
+    Function { instrs, ..func }
+}
+
+/// simplifies [cfg] until nothing changes:
+/// - a `br` whose targets are the same becomes a `jmp`;
+/// - jumps into a block that does nothing but jump elsewhere go there directly;
+/// - a block is merged into its only predecessor when it is that block's only successor.
+/// phis of the successors are renamed to the blocks that now precede them
+pub fn simplify_cfg(cfg: &mut CFG<BasicBlock>) {
+    loop {
+        let changed = fold_branches(cfg) | thread_jumps(cfg) | merge_blocks(cfg);
+        cfg.delete_unreachable();
+        if !changed {
+            break;
+        }
+    }
+    for block in cfg.graph.node_weights_mut() {
+        (block.uses, block.defs) = block.uses_and_defs();
+    }
+    cfg.recompute_defs();
+}
+
+fn label(cfg: &CFG<BasicBlock>, node: NodeIndex) -> String {
+    cfg.graph.node_weight(node).unwrap().label.clone().unwrap()
+}
+
+fn jump(label: String) -> Code {
+    Code::Instruction(Instruction::Effect {
+        args: vec![],
+        funcs: vec![],
+        labels: vec![label],
+        op: EffectOps::Jump,
+        pos: None,
+    })
+}
+
+/// points the edges [from] -> [to] at [target] instead, renaming the label in the terminator
+/// of [from]
+fn retarget(cfg: &mut CFG<BasicBlock>, from: NodeIndex, to: NodeIndex, target: NodeIndex) {
+    let (old, new) = (label(cfg, to), label(cfg, target));
+    if let Some(Code::Instruction(Instruction::Effect { labels, .. })) = cfg
+        .graph
+        .node_weight_mut(from)
+        .unwrap()
+        .instructions
+        .last_mut()
+    {
+        for label in labels.iter_mut() {
+            if *label == old {
+                *label = new.clone();
+            }
+        }
+    }
+    while let Some(edge) = cfg.graph.find_edge(from, to) {
+        let weight = *cfg.graph.edge_weight(edge).unwrap();
+        cfg.graph.remove_edge(edge);
+        cfg.graph.add_edge(from, target, weight);
+    }
+}
+
+/// renames the predecessor [old] to [new] in the phis of [node]
+fn rename_phi_sources(cfg: &mut CFG<BasicBlock>, node: NodeIndex, old: &str, new: &str) {
+    for (_, sources) in cfg.graph.node_weight_mut(node).unwrap().phi.values_mut() {
+        for (_, label) in sources.iter_mut() {
+            if label == old {
+                *label = new.to_string();
+            }
+        }
+    }
+}
+
+/// turns every `br` whose targets are the same into a `jmp`
+fn fold_branches(cfg: &mut CFG<BasicBlock>) -> bool {
+    let mut changed = false;
+    for node in cfg.graph.node_indices() {
+        let block = cfg.graph.node_weight_mut(node).unwrap();
+        if let CF::Branch(t, f) = block.control_flow() {
+            if t == f {
+                *block.instructions.last_mut().unwrap() = jump(t);
+                while let Some(edge) = cfg.graph.first_edge(node, Direction::Outgoing) {
+                    let target = cfg.graph.edge_endpoints(edge).unwrap().1;
+                    cfg.graph.remove_edge(edge);
+                    if cfg.graph.find_edge(node, target).is_none() {
+                        cfg.graph.add_edge(node, target, false);
+                        break;
+                    }
+                }
+                changed = true;
+            }
+        }
+    }
+    changed
+}
+
+/// the block an empty block passes control to, if [node] does nothing else
+fn forwards_to(cfg: &CFG<BasicBlock>, node: NodeIndex) -> Option<NodeIndex> {
+    let block = cfg.graph.node_weight(node).unwrap();
+    let empty = block.phi.is_empty()
+        && match block.instructions.as_slice() {
+            [] => true,
+            [code] => matches!(code.control_flow(), CF::Jump(_)),
+            _ => false,
+        };
+    let mut successors = cfg.graph.neighbors(node);
+    match (successors.next(), successors.next()) {
+        (Some(target), None) if empty && target != node && node != cfg.start() => Some(target),
+        _ => None,
+    }
+}
+
+/// sends the predecessors of every empty block straight to where it jumps. a block with phis
+/// is only jumped to directly if none of the new predecessors already precede it, since its
+/// phis would then need two sources from the same block
+fn thread_jumps(cfg: &mut CFG<BasicBlock>) -> bool {
+    let mut changed = false;
+    for node in cfg.graph.node_indices() {
+        let target = match forwards_to(cfg, node) {
+            Some(target) => target,
+            None => continue,
+        };
+        let preds = cfg
+            .graph
+            .neighbors_directed(node, Direction::Incoming)
+            .collect::<HashSet<_>>();
+        if preds.is_empty() {
+            continue;
+        }
+        if !cfg.graph.node_weight(target).unwrap().phi.is_empty() {
+            let conflict = preds.len() > 1
+                || preds
+                    .iter()
+                    .any(|x| cfg.graph.find_edge(*x, target).is_some());
+            if conflict {
+                continue;
+            }
+        }
+        let node_label = label(cfg, node);
+        for pred in preds {
+            retarget(cfg, pred, node, target);
+            let pred_label = label(cfg, pred);
+            rename_phi_sources(cfg, target, &node_label, &pred_label);
+        }
+        changed = true;
+    }
+    changed
+}
+
+/// merges every block into its only predecessor when it is that predecessor's only successor
+fn merge_blocks(cfg: &mut CFG<BasicBlock>) -> bool {
+    let mut changed = false;
+    for node in cfg.graph.node_indices() {
+        if node == cfg.start() {
+            continue;
+        }
+        let mut preds = cfg.graph.neighbors_directed(node, Direction::Incoming);
+        let pred = match (preds.next(), preds.next()) {
+            (Some(pred), None) if pred != node => pred,
+            _ => continue,
+        };
+        //the entry block stays empty, when flattened code is read back in its label is dropped
+        //and phis naming it would lose their predecessor
+        if pred == cfg.start() {
+            continue;
+        }
+        let mut succs = cfg.graph.edges(pred);
+        match (succs.next(), succs.next()) {
+            (Some(_), None) => (),
+            _ => continue,
+        }
+
+        let block = cfg.graph.node_weight_mut(node).unwrap();
+        //with a single predecessor a phi is just a copy
+        let copies = block
+            .phi
+            .drain()
+            .map(|(canonical, (dest, sources))| {
+                Code::Instruction(Instruction::Value {
+                    args: vec![sources[0].0.clone()],
+                    dest,
+                    funcs: vec![],
+                    labels: vec![],
+                    op: ValueOps::Id,
+                    pos: None,
+                    op_type: block.vartype[&canonical].clone(),
+                })
+            })
+            .collect::<Vec<_>>();
+        let instructions = std::mem::take(&mut block.instructions);
+        let (node_label, pred_label) = (label(cfg, node), label(cfg, pred));
+
+        let pred_block = cfg.graph.node_weight_mut(pred).unwrap();
+        if let CF::Jump(_) = pred_block.control_flow() {
+            pred_block.instructions.pop();
+        }
+        pred_block.instructions.extend(copies);
+        pred_block.instructions.extend(instructions);
+
+        cfg.graph
+            .remove_edge(cfg.graph.find_edge(pred, node).unwrap());
+        let succs = cfg
+            .graph
+            .edges(node)
+            .map(|x| (x.target(), *x.weight()))
+            .collect::<Vec<_>>();
+        for (succ, weight) in succs {
+            cfg.graph.add_edge(pred, succ, weight);
+            rename_phi_sources(cfg, succ, &node_label, &pred_label);
+        }
+        while let Some(edge) = cfg.graph.first_edge(node, Direction::Outgoing) {
+            cfg.graph.remove_edge(edge);
+        }
+        changed = true;
+    }
+    changed
+}
+
+/// removes the labels of [code] that nothing jumps to or names in a phi. the label at the end
+/// stays, a function that ends with a jump isn't followed by every interpreter
+pub fn drop_unused_labels(code: &mut Vec<Code>) {
+    let used = code
+        .iter()
+        .flat_map(|x| match x {
+            Code::Instruction(Instruction::Effect { labels, .. })
+            | Code::Instruction(Instruction::Value { labels, .. }) => labels.clone(),
+            _ => vec![],
+        })
+        .collect::<HashSet<_>>();
+    let last = code.len();
+    let mut i = 0;
+    code.retain(|x| {
+        i += 1;
+        match x {
+            Code::Label { label, .. } => i == last || used.contains(label),
+            _ => true,
+        }
+    });
+}
@@ -1,7 +1,12 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
-use crate::{cfg::CFG, dominator::dominator_analyis, utils::BasicBlock};
-use petgraph::{algo::dominators::Dominators, graph::NodeIndex};
+use crate::{
+    cfg::CFG,
+    dominator::dominator_analyis,
+    utils::{BasicBlock, CFGNode, CF},
+};
+use bril_rs::{Code, ConstOps, Instruction, Literal, Type, ValueOps};
+use petgraph::{algo::dominators::Dominators, graph::NodeIndex, Direction};
 
 pub fn to_ssa(cfg: &CFG<BasicBlock>) -> CFG<BasicBlock> {
     let mut ssa = cfg.clone();
@@ -135,3 +140,288 @@ fn rename_vars(
 
     pop_defs(stack, &new_def_ct);
 }
+
+/// lowers phi nodes back into copies on predecessor edges. critical edges are split so copies
+/// only execute on the edge they belong to, and each edge's copies are ordered as a parallel copy
+pub fn from_ssa(cfg: &CFG<BasicBlock>) -> CFG<BasicBlock> {
+    let mut out = cfg.clone();
+    remove_dead_phis(&mut out);
+    let mut labels = out
+        .graph
+        .node_weights()
+        .filter_map(|x| x.label.clone())
+        .collect::<HashSet<_>>();
+    let mut temp = 0;
+
+    //number of reads of each variable, a phi source read only by its phi can be coalesced
+    let mut reads = HashMap::<String, usize>::new();
+    for block in out.graph.node_weights() {
+        let phi_uses = block
+            .phi
+            .values()
+            .flat_map(|(_, phi_source)| phi_source.iter().map(|(var, _)| var.clone()));
+        let ins_uses = block.instructions.iter().flat_map(|x| x.uses());
+        for var in phi_uses.chain(ins_uses) {
+            *reads.entry(var).or_default() += 1;
+        }
+    }
+
+    let phi_blocks = out
+        .graph
+        .node_indices()
+        .filter(|x| !out.graph.node_weight(*x).unwrap().phi.is_empty())
+        .collect::<Vec<_>>();
+
+    for block_idx in phi_blocks {
+        let mut preds = out
+            .graph
+            .neighbors_directed(block_idx, Direction::Incoming)
+            .collect::<Vec<_>>();
+        preds.sort();
+        preds.dedup();
+
+        for pred in preds {
+            let pred_label = out.graph.node_weight(pred).unwrap().label.clone().unwrap();
+            let mut copies = phi_copies(out.graph.node_weight(block_idx).unwrap(), &pred_label);
+
+            //a branch has several successors, so its copies need a block of their own
+            let target = match out.graph.node_weight(pred).unwrap().control_flow() {
+                CF::Branch(_, _) => out.split_edge(pred, block_idx, "_SSA_E", &mut labels),
+                _ => {
+                    coalesce_copies(
+                        out.graph.node_weight_mut(pred).unwrap(),
+                        &mut copies,
+                        &reads,
+                    );
+                    pred
+                }
+            };
+            if copies.is_empty() {
+                continue;
+            }
+            let copies = sequentialize_copies(copies, &mut temp);
+            insert_copies(out.graph.node_weight_mut(target).unwrap(), copies);
+        }
+    }
+
+    for block in out.graph.node_weights_mut() {
+        block.phi.clear();
+        (block.uses, block.defs) = block.uses_and_defs();
+    }
+    out.recompute_defs();
+    out
+}
+
+/// moves the `phi` instructions at the start of each block of [cfg] into [BasicBlock::phi], so
+/// a function that was flattened in SSA form can be optimized as SSA again. each phi is keyed by
+/// its destination, the original variable is gone. returns true if there were any phis
+pub fn lift_phis(cfg: &mut CFG<BasicBlock>) -> bool {
+    let mut lifted = false;
+    for block in cfg.graph.node_weights_mut() {
+        let count = block
+            .instructions
+            .iter()
+            .take_while(|x| {
+                matches!(
+                    x,
+                    Code::Instruction(Instruction::Value {
+                        op: ValueOps::Phi,
+                        ..
+                    })
+                )
+            })
+            .count();
+        for code in block.instructions.drain(..count).collect::<Vec<_>>() {
+            if let Code::Instruction(Instruction::Value {
+                args,
+                dest,
+                labels,
+                op_type,
+                ..
+            }) = code
+            {
+                block.vartype.insert(dest.clone(), op_type);
+                let phi_source = args.into_iter().zip(labels).collect();
+                block.phi.insert(dest.clone(), (dest, phi_source));
+                lifted = true;
+            }
+        }
+        (block.uses, block.defs) = block.uses_and_defs();
+    }
+    cfg.recompute_defs();
+    lifted
+}
+
+/// removes phis whose result is never read outside of other dead phis. to_ssa places phis
+/// without checking liveness, and lowering those would read variables that may be undefined
+pub fn remove_dead_phis(cfg: &mut CFG<BasicBlock>) {
+    let mut live = cfg
+        .graph
+        .node_weights()
+        .flat_map(|x| x.instructions.iter().flat_map(|ins| ins.uses()))
+        .collect::<HashSet<_>>();
+
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for block in cfg.graph.node_weights() {
+            for (phi_dest, phi_source) in block.phi.values() {
+                if live.contains(phi_dest) {
+                    for (var, _) in phi_source {
+                        changed |= live.insert(var.clone());
+                    }
+                }
+            }
+        }
+    }
+
+    for block in cfg.graph.node_weights_mut() {
+        block.phi.retain(|_, (phi_dest, _)| live.contains(phi_dest));
+    }
+}
+
+/// copies (dest, src, type) that the phis of [block] perform when entered from [pred_label].
+/// a phi with no source for [pred_label] gets [None], the variable is undefined on that edge
+fn phi_copies(block: &BasicBlock, pred_label: &String) -> Vec<(String, Option<String>, Type)> {
+    let mut copies = block
+        .phi
+        .iter()
+        .map(|(canonical, (phi_dest, phi_source))| {
+            (
+                phi_dest.clone(),
+                phi_source
+                    .iter()
+                    .find(|(_, label)| label == pred_label)
+                    .map(|(var, _)| var.clone()),
+                block.vartype.get(canonical).unwrap().clone(),
+            )
+        })
+        .collect::<Vec<_>>();
+    copies.sort_by(|a, b| a.0.cmp(&b.0));
+    copies
+}
+
+/// drops the copies whose source is computed in [block] and read nowhere else by making that
+/// instruction define the phi directly. [block] must have the phi's block as its only successor
+fn coalesce_copies(
+    block: &mut BasicBlock,
+    copies: &mut Vec<(String, Option<String>, Type)>,
+    reads: &HashMap<String, usize>,
+) {
+    let sources = copies
+        .iter()
+        .filter_map(|(_, src, _)| src.clone())
+        .collect::<HashSet<_>>();
+    copies.retain(|(dest, src, _)| {
+        let src = match src {
+            Some(src) if reads.get(src) == Some(&1) && !sources.contains(dest) => src,
+            _ => return true,
+        };
+        let def = block
+            .instructions
+            .iter()
+            .position(|x| x.defs().contains(src));
+        let def = match def {
+            Some(def) => def,
+            None => return true,
+        };
+        //the phi keeps its old value until the end of the block
+        if block.instructions[def + 1..]
+            .iter()
+            .any(|x| x.uses().contains(dest) || x.defs().contains(dest))
+        {
+            return true;
+        }
+        match &mut block.instructions[def] {
+            Code::Instruction(
+                Instruction::Constant { dest: d, .. } | Instruction::Value { dest: d, .. },
+            ) => *d = dest.clone(),
+            _ => return true,
+        }
+        false
+    });
+}
+
+/// orders a parallel copy so that no destination is overwritten before every copy reading it
+/// has run. cycles (e.g. swaps) are broken by saving one destination in a fresh temporary.
+/// destinations without a source are given a placeholder constant last, so that copies through
+/// them on later edges never read an undefined variable
+fn sequentialize_copies(
+    copies: Vec<(String, Option<String>, Type)>,
+    temp: &mut usize,
+) -> Vec<Code> {
+    let mut seq = vec![];
+    let mut undefined = vec![];
+    let mut pending = vec![];
+    for (dest, src, op_type) in copies {
+        match src {
+            Some(src) if src == dest => (),
+            Some(src) => pending.push((dest, src, op_type)),
+            None => undefined.push((dest, op_type)),
+        }
+    }
+
+    while !pending.is_empty() {
+        let ready = pending
+            .iter()
+            .position(|(dest, _, _)| !pending.iter().any(|(_, src, _)| src == dest));
+        match ready {
+            Some(i) => {
+                let (dest, src, op_type) = pending.remove(i);
+                seq.push(copy(dest, src, op_type));
+            }
+            None => {
+                let (dest, _, op_type) = pending[0].clone();
+                let tmp = format!("_ssa_tmp{}", temp);
+                *temp += 1;
+                seq.push(copy(tmp.clone(), dest.clone(), op_type));
+                for (_, src, _) in pending.iter_mut() {
+                    if *src == dest {
+                        *src = tmp.clone();
+                    }
+                }
+            }
+        }
+    }
+
+    for (dest, const_type) in undefined {
+        let value = match const_type {
+            Type::Int => Literal::Int(0),
+            Type::Bool => Literal::Bool(false),
+            Type::Float => Literal::Float(0.0),
+            Type::Char => Literal::Char('\0'),
+            //there is no null pointer to stand in, leave it undefined
+            Type::Pointer(_) => continue,
+        };
+        seq.push(Code::Instruction(Instruction::Constant {
+            dest,
+            op: ConstOps::Const,
+            pos: None,
+            const_type,
+            value,
+        }));
+    }
+    seq
+}
+fn copy(dest: String, src: String, op_type: Type) -> Code {
+    Code::Instruction(Instruction::Value {
+        args: vec![src],
+        dest,
+        funcs: vec![],
+        labels: vec![],
+        op: ValueOps::Id,
+        pos: None,
+        op_type,
+    })
+}
+
+/// places [copies] at the end of [block], ahead of its terminator
+fn insert_copies(block: &mut BasicBlock, copies: Vec<Code>) {
+    let at = match block.instructions.last().map(|x| x.control_flow()) {
+        Some(CF::Jump(_)) | Some(CF::Branch(_, _)) | Some(CF::Return) => {
+            block.instructions.len() - 1
+        }
+        _ => block.instructions.len(),
+    };
+    block.instructions.splice(at..at, copies);
+}
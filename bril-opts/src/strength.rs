@@ -0,0 +1,324 @@
+use std::collections::{HashMap, HashSet};
+
+use bril_rs::{Code, ConstOps, Instruction, Literal, Type, ValueOps};
+use petgraph::{algo::dominators::Dominators, graph::NodeIndex, Direction};
+
+use crate::cfg::CFG;
+use crate::dominator::dominator_analyis;
+use crate::induction::{induction_vars, Invariant, Linear};
+use crate::loops::{find_loops, Loop};
+use crate::utils::{BasicBlock, CFGNode, CF};
+
+/// strength reduction over a CFG in SSA form (see [crate::ssa::to_ssa]). an induction variable
+/// computed with `mul` or `ptradd` from a basic induction variable gets its own header phi that
+/// is stepped with an `add` (or `ptradd`) at the end of every latch, and the computations it
+/// replaces are removed. loops without a preheader or with a latch ending in a branch are skipped
+pub fn strength_reduce(cfg: &CFG<BasicBlock>) -> CFG<BasicBlock> {
+    let mut out = cfg.clone();
+    let forest = find_loops(&out);
+    let (dominators, _) = dominator_analyis(&out);
+    let mut names = out
+        .graph
+        .node_weights()
+        .map(|x| {
+            x.phi
+                .values()
+                .map(|(dest, _)| dest.clone())
+                .chain(x.instructions.iter().map(|x| x.defs()).flatten())
+                .collect::<Vec<_>>()
+        })
+        .flatten()
+        .chain(out.args.iter().map(|x| x.name.clone()))
+        .collect::<HashSet<_>>();
+
+    for l in forest.inner_to_outer() {
+        let l = &forest.loops[l];
+        if l.preheader.is_some() {
+            reduce_loop(&mut out, l, &dominators, &mut names);
+        }
+    }
+
+    for block in out.graph.node_weights_mut() {
+        (block.uses, block.defs) = block.uses_and_defs();
+    }
+    out.recompute_defs();
+    out
+}
+
+struct Builder<'a> {
+    names: &'a mut HashSet<String>,
+    code: Vec<Code>,
+}
+
+impl<'a> Builder<'a> {
+    fn fresh(&mut self) -> String {
+        let mut count = 0;
+        while self.names.contains(&format!("_sr{}", count)) {
+            count += 1;
+        }
+        let name = format!("_sr{}", count);
+        self.names.insert(name.clone());
+        name
+    }
+
+    fn value(&mut self, op: ValueOps, args: Vec<String>, op_type: Type) -> String {
+        let dest = self.fresh();
+        self.code.push(Code::Instruction(Instruction::Value {
+            args,
+            dest: dest.clone(),
+            funcs: vec![],
+            labels: vec![],
+            op,
+            pos: None,
+            op_type,
+        }));
+        dest
+    }
+
+    /// emits the instructions computing [inv], returns the variable holding it
+    fn materialize(&mut self, inv: &Invariant) -> String {
+        let (op, a, b) = match inv {
+            Invariant::Var(var) => return var.clone(),
+            Invariant::Const(n) => {
+                let dest = self.fresh();
+                self.code.push(Code::Instruction(Instruction::Constant {
+                    dest: dest.clone(),
+                    op: ConstOps::Const,
+                    pos: None,
+                    const_type: Type::Int,
+                    value: Literal::Int(*n),
+                }));
+                return dest;
+            }
+            Invariant::Add(a, b) => (ValueOps::Add, a, b),
+            Invariant::Sub(a, b) => (ValueOps::Sub, a, b),
+            Invariant::Mul(a, b) => (ValueOps::Mul, a, b),
+        };
+        let args = vec![self.materialize(a), self.materialize(b)];
+        self.value(op, args, Type::Int)
+    }
+}
+
+fn reduce_loop(
+    cfg: &mut CFG<BasicBlock>,
+    l: &Loop,
+    dominators: &Dominators<NodeIndex>,
+    names: &mut HashSet<String>,
+) {
+    let ivs = induction_vars(cfg, l, dominators);
+    let derived = ivs
+        .linear
+        .keys()
+        .filter(|x| !ivs.basic.contains_key(*x))
+        .cloned()
+        .collect::<HashSet<_>>();
+
+    //variables read by something other than the definition of another induction variable,
+    //along with their types
+    let mut roots = HashSet::new();
+    let mut types = HashMap::new();
+    for block in cfg.graph.node_weights() {
+        for (_, phi_source) in block.phi.values() {
+            for (var, _) in phi_source {
+                roots.insert(var.clone());
+            }
+        }
+        for code in &block.instructions {
+            if let Code::Instruction(Instruction::Value { dest, op_type, .. }) = code {
+                types.insert(dest.clone(), op_type.clone());
+            }
+            if code.defs().iter().all(|x| !derived.contains(x)) {
+                for var in code.uses() {
+                    roots.insert(var);
+                }
+            }
+        }
+    }
+    //stepping the new phi costs an add, which only pays off if it replaces a multiplication
+    //or the index arithmetic of a pointer
+    let profitable = |x: &Linear| match (&x.scale, &x.base) {
+        (Invariant::Const(1) | Invariant::Const(-1), None) => false,
+        (Invariant::Const(1), Some(_)) => x.offset != Invariant::Const(0),
+        _ => true,
+    };
+    let mut reduce = derived
+        .iter()
+        .filter(|x| roots.contains(*x) && profitable(&ivs.linear[*x]))
+        .cloned()
+        .collect::<Vec<_>>();
+    if reduce.is_empty() {
+        return;
+    }
+    reduce.sort();
+
+    let preheader = l.preheader.unwrap();
+    let preheader_label = cfg
+        .graph
+        .node_weight(preheader)
+        .unwrap()
+        .label
+        .clone()
+        .unwrap();
+    let latches = cfg
+        .graph
+        .neighbors_directed(l.header, Direction::Incoming)
+        .filter(|x| l.contains(*x))
+        .collect::<Vec<_>>();
+    //a back edge leaving a branch is split out of SSA, so the copy into the new phi can't be
+    //coalesced with its step and the reduction saves nothing
+    if latches.iter().any(|x| {
+        matches!(
+            cfg.graph.node_weight(*x).unwrap().control_flow(),
+            CF::Branch(_, _)
+        )
+    }) {
+        return;
+    }
+
+    let mut pre = Builder {
+        names,
+        code: vec![],
+    };
+    //the new phi replacing each form
+    let mut reduced = HashMap::<Linear, String>::new();
+    //steps to insert at the end of each latch
+    let mut steps = HashMap::<NodeIndex, Vec<Code>>::new();
+    let mut phis = vec![];
+    let mut rename = HashMap::new();
+    for var in reduce {
+        let form = ivs.linear[&var].clone();
+        if let Some(phi) = reduced.get(&form) {
+            rename.insert(var, phi.clone());
+            continue;
+        }
+        let basic = &ivs.basic[&form.iv];
+        let op_type = types[&var].clone();
+
+        let start = Invariant::add(
+            Invariant::mul(basic.init.clone(), form.scale.clone()),
+            form.offset.clone(),
+        );
+        let mut init = pre.materialize(&start);
+        let step = pre.materialize(&Invariant::mul(basic.step.clone(), form.scale.clone()));
+        let step_op = match &form.base {
+            Some(base) => {
+                let base = pre.materialize(base);
+                init = pre.value(ValueOps::PtrAdd, vec![base, init], op_type.clone());
+                ValueOps::PtrAdd
+            }
+            None => ValueOps::Add,
+        };
+
+        let phi = pre.fresh();
+        let mut phi_source = vec![(init, preheader_label.clone())];
+        for latch in &latches {
+            let next = pre.fresh();
+            steps
+                .entry(*latch)
+                .or_default()
+                .push(Code::Instruction(Instruction::Value {
+                    args: vec![phi.clone(), step.clone()],
+                    dest: next.clone(),
+                    funcs: vec![],
+                    labels: vec![],
+                    op: step_op,
+                    pos: None,
+                    op_type: op_type.clone(),
+                }));
+            let label = cfg
+                .graph
+                .node_weight(*latch)
+                .unwrap()
+                .label
+                .clone()
+                .unwrap();
+            phi_source.push((next, label));
+        }
+        phis.push((phi.clone(), phi_source, op_type));
+
+        reduced.insert(form, phi.clone());
+        rename.insert(var, phi);
+    }
+
+    let block = cfg.graph.node_weight_mut(preheader).unwrap();
+    let at = match block.control_flow() {
+        CF::Jump(_) | CF::Branch(_, _) | CF::Return => block.instructions.len() - 1,
+        _ => block.instructions.len(),
+    };
+    block.instructions.splice(at..at, pre.code);
+
+    let header = cfg.graph.node_weight_mut(l.header).unwrap();
+    for (phi, phi_source, op_type) in phis {
+        header.vartype.insert(phi.clone(), op_type);
+        header.phi.insert(phi.clone(), (phi, phi_source));
+    }
+
+    //the new phi holds its value for the whole iteration, so stepping it just before the back
+    //edge keeps it in line with the basic variable's phi
+    for (latch, step) in steps {
+        let block = cfg.graph.node_weight_mut(latch).unwrap();
+        let at = match block.control_flow() {
+            CF::Jump(_) | CF::Return => block.instructions.len() - 1,
+            _ => block.instructions.len(),
+        };
+        block.instructions.splice(at..at, step);
+    }
+
+    //the new phis dominate every use of the variables they replace
+    for block in cfg.graph.node_weights_mut() {
+        for (_, phi_source) in block.phi.values_mut() {
+            for (var, _) in phi_source.iter_mut() {
+                if let Some(phi) = rename.get(var) {
+                    *var = phi.clone();
+                }
+            }
+        }
+        for code in block.instructions.iter_mut() {
+            if let Code::Instruction(
+                Instruction::Value { args, .. } | Instruction::Effect { args, .. },
+            ) = code
+            {
+                for arg in args.iter_mut() {
+                    if let Some(phi) = rename.get(arg) {
+                        *arg = phi.clone();
+                    }
+                }
+            }
+        }
+    }
+
+    remove_dead(cfg, l, &derived);
+}
+
+/// removes definitions of [candidates] in [l] that are no longer read
+fn remove_dead(cfg: &mut CFG<BasicBlock>, l: &Loop, candidates: &HashSet<String>) {
+    loop {
+        let used = cfg
+            .graph
+            .node_weights()
+            .map(|x| {
+                x.phi
+                    .values()
+                    .map(|(_, phi_source)| phi_source.iter().map(|(var, _)| var.clone()))
+                    .flatten()
+                    .chain(x.instructions.iter().map(|x| x.uses()).flatten())
+                    .collect::<Vec<_>>()
+            })
+            .flatten()
+            .collect::<HashSet<_>>();
+        let mut changed = false;
+        for node in l.body.iter() {
+            let block = cfg.graph.node_weight_mut(*node).unwrap();
+            let before = block.instructions.len();
+            block.instructions.retain(|x| {
+                x.defs()
+                    .iter()
+                    .all(|x| !candidates.contains(x) || used.contains(x))
+            });
+            changed |= block.instructions.len() != before;
+        }
+        if !changed {
+            break;
+        }
+    }
+}
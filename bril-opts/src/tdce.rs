@@ -1,19 +1,25 @@
+use crate::effects::{summarize, Summaries};
 use crate::utils::{code_to_bb, CFGNode};
 use bril_rs::{Code, Function, Program};
 use std::collections::{HashMap, HashSet};
 
 pub fn global_pass(prog: Program) -> Program {
+    let summaries = summarize(&prog);
     Program {
-        functions: prog.functions.into_iter().map(|x| global_tdce(x)).collect(),
+        functions: prog
+            .functions
+            .into_iter()
+            .map(|x| global_tdce(x, &summaries))
+            .collect(),
         imports: prog.imports,
     }
 }
 
-fn global_tdce(func: Function) -> Function {
+fn global_tdce(func: Function, summaries: &Summaries) -> Function {
     let mut code = func.instrs;
     let mut changed = true;
     while changed {
-        (changed, code) = global_tdce_single_pass(code);
+        (changed, code) = global_tdce_single_pass(code, summaries);
     }
 
     Function {
@@ -22,7 +28,7 @@ fn global_tdce(func: Function) -> Function {
     }
 }
 
-fn global_tdce_single_pass(code: Vec<Code>) -> (bool, Vec<Code>) {
+fn global_tdce_single_pass(code: Vec<Code>, summaries: &Summaries) -> (bool, Vec<Code>) {
     let used = code
         .iter()
         .map(|x| x.uses())
@@ -34,7 +40,8 @@ fn global_tdce_single_pass(code: Vec<Code>) -> (bool, Vec<Code>) {
         .enumerate()
         .map(|(i, x)| {
             if let Some(def) = x.defs().iter().next().cloned() {
-                if !used.contains(&def) {
+                //an unused result doesn't make a call or alloc go away
+                if !used.contains(&def) && summaries.code(x).is_removable() {
                     Some(i)
                 } else {
                     None
@@ -64,20 +71,25 @@ fn global_tdce_single_pass(code: Vec<Code>) -> (bool, Vec<Code>) {
 }
 
 pub fn local_pass(prog: Program) -> Program {
+    let summaries = summarize(&prog);
     Program {
-        functions: prog.functions.into_iter().map(|x| local_tdce(x)).collect(),
+        functions: prog
+            .functions
+            .into_iter()
+            .map(|x| local_tdce(x, &summaries))
+            .collect(),
         imports: prog.imports,
     }
 }
 
-fn local_tdce(func: Function) -> Function {
+fn local_tdce(func: Function, summaries: &Summaries) -> Function {
     let local_dce_pass = code_to_bb(func.instrs)
         .into_iter()
         .map(|block| {
             let mut changed = true;
-            let mut block = block.instructions;
+            let mut block = block.as_code_block();
             while changed {
-                (changed, block) = local_tdce_single_pass(block);
+                (changed, block) = local_tdce_single_pass(block, summaries);
             }
             block
         })
@@ -90,7 +102,7 @@ fn local_tdce(func: Function) -> Function {
     }
 }
 
-fn local_tdce_single_pass(code: Vec<Code>) -> (bool, Vec<Code>) {
+fn local_tdce_single_pass(code: Vec<Code>, summaries: &Summaries) -> (bool, Vec<Code>) {
     let mut last_def = HashMap::new();
     let mut to_delete = HashSet::new();
     for (line, ins) in code.iter().enumerate() {
@@ -101,7 +113,9 @@ fn local_tdce_single_pass(code: Vec<Code>) -> (bool, Vec<Code>) {
         //check for defs
         if let Some(def) = ins.defs().iter().next().cloned() {
             if let Some(idx) = last_def.insert(def, line) {
-                to_delete.insert(idx);
+                if summaries.code(&code[idx]).is_removable() {
+                    to_delete.insert(idx);
+                }
             }
         }
     }
@@ -114,7 +114,7 @@ impl BasicBlock {
                 pos: None,
             });
         }
-        for (canonical, (phi_dest, phi_source)) in self.phi.iter() {
+        for (canonical, (phi_dest, phi_source)) in self.phi_order() {
             let args = phi_source
                 .iter()
                 .map(|(var, _)| var.clone())
@@ -137,6 +137,28 @@ impl BasicBlock {
         code
     }
 
+    /// the phis of the block in the order they are printed. interpreters run phis one after
+    /// another, so a phi that reads the destination of another phi comes before it. phis that
+    /// read each other in a cycle can't be ordered and stay sorted by destination
+    fn phi_order(&self) -> Vec<(&String, &(String, Vec<(String, String)>))> {
+        let mut remaining = self.phi.iter().collect::<Vec<_>>();
+        remaining.sort_by(|a, b| a.1 .0.cmp(&b.1 .0));
+        let mut order = vec![];
+        while !remaining.is_empty() {
+            let read = |dest: &String| {
+                remaining.iter().any(|(_, (other, sources))| {
+                    other != dest && sources.iter().any(|x| &x.0 == dest)
+                })
+            };
+            let i = remaining
+                .iter()
+                .position(|(_, (dest, _))| !read(dest))
+                .unwrap_or(0);
+            order.push(remaining.remove(i));
+        }
+        order
+    }
+
     fn value_type(code: &Code) -> Option<(String, bril_rs::Type)> {
         match code {
             Code::Label { .. } => None,
@@ -205,6 +227,7 @@ pub fn code_to_bb_extra_args(stmts: Vec<Code>, init_types: &Vec<Argument>) -> Ve
             }
         }
     }
+    (block.uses, block.defs) = block.uses_and_defs();
     blocks.push(block);
 
     for arg in init_types {
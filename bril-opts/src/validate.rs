@@ -0,0 +1,205 @@
+use std::fmt::Display;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::mpsc;
+use std::time::Duration;
+
+use bril_rs::{Code, Function, Instruction, Program, ValueOps};
+use brilirs::basic_block::BBProgram;
+use brilirs::{check, interp};
+
+use crate::cfg::graph_from_function;
+use crate::ssa::{from_ssa, lift_phis};
+
+/// how long [run] waits for a program by default
+pub const TIME_BUDGET: Duration = Duration::from_secs(10);
+
+/// stack of the thread brilirs runs on, deep recursion in a program is deep recursion in the
+/// interpreter
+const STACK_SIZE: usize = 256 << 20;
+
+/// what brilirs observed when running a program
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Run {
+    pub output: String,
+    /// the type error, runtime error or panic the run ended with, if any
+    pub error: Option<String>,
+    /// dynamic instruction count, only known if the run finished
+    pub dyn_count: Option<u64>,
+    /// the run was given up on, it didn't finish within its budget
+    pub timed_out: bool,
+}
+
+impl Run {
+    /// returns true if [other] printed the same and failed the same way, the instruction counts
+    /// may differ
+    pub fn same_behavior(&self, other: &Run) -> bool {
+        self.output == other.output && self.error == other.error
+    }
+}
+
+/// [prog] with every function that has phis converted out of SSA. the phis of a block read
+/// their sources in parallel, brilirs evaluates them one after another
+fn without_phis(prog: &Program) -> Program {
+    let functions = prog
+        .functions
+        .iter()
+        .map(|func| {
+            let mut cfg = graph_from_function(func);
+            match lift_phis(&mut cfg) {
+                true => Function {
+                    instrs: from_ssa(&cfg).flatten(),
+                    ..func.clone()
+                },
+                false => func.clone(),
+            }
+        })
+        .collect();
+    Program {
+        functions,
+        imports: prog.imports.clone(),
+    }
+}
+
+fn execute(prog: &Program, args: &[String]) -> Run {
+    let mut output = vec![];
+    let mut profile = vec![];
+    let result = catch_unwind(AssertUnwindSafe(|| {
+        let bbprog = BBProgram::try_from(prog.clone()).map_err(|e| e.to_string())?;
+        check::type_check(&bbprog).map_err(|e| format!("type error: {}", e))?;
+        interp::execute_main(&bbprog, &mut output, args, true, &mut profile, false)
+            .map_err(|e| e.to_string())
+    }));
+    let error = match result {
+        Ok(Ok(())) => None,
+        Ok(Err(e)) => Some(e),
+        Err(_) => Some("the interpreter panicked".to_string()),
+    };
+    let dyn_count = String::from_utf8_lossy(&profile)
+        .trim()
+        .strip_prefix("total_dyn_inst: ")
+        .and_then(|x| x.parse().ok());
+    Run {
+        output: String::from_utf8_lossy(&output).into_owned(),
+        error,
+        dyn_count,
+        timed_out: false,
+    }
+}
+
+/// type checks [prog] and runs its main function with [args] in brilirs, converting it out of
+/// SSA first if it has phis. the run is given up on after [budget]; brilirs can't be stopped,
+/// so it goes on in the background until the process exits
+pub fn run(prog: &Program, args: &[String], budget: Duration) -> Run {
+    let (send, receive) = mpsc::channel();
+    let prog = without_phis(prog);
+    let args = args.to_vec();
+    let spawned = std::thread::Builder::new()
+        .stack_size(STACK_SIZE)
+        .spawn(move || {
+            let _ = send.send(execute(&prog, &args));
+        });
+    let timed_out = Run {
+        output: String::new(),
+        error: Some(format!("didn't finish within {:?}", budget)),
+        dyn_count: None,
+        timed_out: true,
+    };
+    match spawned {
+        Ok(_) => receive.recv_timeout(budget).unwrap_or(timed_out),
+        Err(e) => Run {
+            error: Some(format!("can't start the interpreter: {}", e)),
+            timed_out: false,
+            ..timed_out
+        },
+    }
+}
+
+pub(crate) fn has_phis(prog: &Program) -> bool {
+    prog.functions.iter().flat_map(|x| &x.instrs).any(|x| {
+        matches!(
+            x,
+            Code::Instruction(Instruction::Value {
+                op: ValueOps::Phi,
+                ..
+            })
+        )
+    })
+}
+
+/// a pass that changed what the program does
+#[derive(Debug, Clone)]
+pub struct Divergence {
+    pub pass: String,
+    pub expected: Run,
+    pub found: Run,
+}
+
+fn count(run: &Run) -> String {
+    run.dyn_count.map_or("?".to_string(), |x| x.to_string())
+}
+
+impl Display for Divergence {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "`{}` changed the behavior of the program", self.pass)?;
+        writeln!(f, "expected output:\n{}", self.expected.output)?;
+        writeln!(f, "found output:\n{}", self.found.output)?;
+        if self.expected.error != self.found.error {
+            writeln!(f, "expected error: {:?}", self.expected.error)?;
+            writeln!(f, "found error: {:?}", self.found.error)?;
+        }
+        write!(
+            f,
+            "dynamic instructions: {} -> {}",
+            count(&self.expected),
+            count(&self.found)
+        )
+    }
+}
+
+/// translation validation, compares every version of a program with the original by running
+/// both on the same arguments
+pub struct Validator {
+    args: Vec<String>,
+    budget: Duration,
+    pub expected: Run,
+}
+
+impl Validator {
+    /// runs the original [prog] with [args], every run gets [budget] to finish
+    pub fn new(prog: &Program, args: Vec<String>, budget: Duration) -> Self {
+        let expected = run(prog, &args, budget);
+        Validator {
+            args,
+            budget,
+            expected,
+        }
+    }
+
+    /// runs [prog], the result of [pass], and returns the divergence if it doesn't behave like
+    /// the original, a run that doesn't finish within the budget included. nothing is run if
+    /// the original didn't finish, there is no behavior to compare with
+    pub fn check(&self, pass: &str, prog: &Program) -> Result<Option<Run>, Divergence> {
+        if self.expected.timed_out {
+            return Ok(None);
+        }
+        let found = run(prog, &self.args, self.budget);
+        if self.expected.same_behavior(&found) {
+            Ok(Some(found))
+        } else {
+            Err(Divergence {
+                pass: pass.to_string(),
+                expected: self.expected.clone(),
+                found,
+            })
+        }
+    }
+
+    /// one line comparing the dynamic instruction count of [found] with the original
+    pub fn summary(&self, found: &Run) -> String {
+        format!(
+            "validated: same output, {} -> {} dynamic instructions",
+            count(&self.expected),
+            count(found)
+        )
+    }
+}